@@ -1,5 +1,18 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use bitflags::bitflags;
-use osl::math;
+use osl::{error::Result, math, sleep::usleep};
+
+use osl::sync::{Arc, GeneralComplete, OslCompletion};
+#[cfg(feature = "arceos")]
+use osl::{
+    error::{to_error, Errno},
+    sync::{SpinLock, new_spinlock},
+};
+
+/// Time a polled transfer may take before it is given up
+#[cfg(feature = "arceos")]
+const DW_I2C_XFER_TIMEOUT_US: u64 = 1_000_000;
 
 #[allow(dead_code)]
 #[derive(Default, Debug, Copy, Clone)]
@@ -73,6 +86,17 @@ impl DwI2cSclLHCnt {
     }
 }
 
+/// SMBus timeout counts, in ic_clk cycles
+#[derive(Default, Debug, Copy, Clone)]
+pub(crate) struct DwI2cSmbusCnt {
+    /// tLOW:SEXT, cumulative clock low extension of a target per message
+    pub(crate) clk_low_sext: u32,
+    /// tLOW:MEXT, cumulative clock low extension of the master per byte
+    pub(crate) clk_low_mext: u32,
+    /// tHIGH:MAX, bus idle detection
+    pub(crate) thigh_max_idle: u16,
+}
+
 bitflags! {
     /// I2C DRIVER STATUS
     #[repr(transparent)]
@@ -82,6 +106,7 @@ bitflags! {
          const ACTIVE           = 1<<0;
          const WriteInProgress  = 1<<1;
          const ReadInProgress   = 1<<2;
+         const GeneralCallInProgress = 1<<3;
     }
 }
 
@@ -91,5 +116,138 @@ bitflags! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     pub(crate) struct DwI2cCmdErr: u32 {
         const TX_ABRT = 0x1;
+        const CLK_EXTND_TIMEOUT = 0x2;
+        const USER_ABRT = 0x4;
+    }
+}
+
+/// Sleeping bus lock serializing the transfers of an adapter
+///
+/// Transfers sleep while waiting for completion, so a spinlock can not
+/// be held across them. Waiters poll, there is no fairness guarantee.
+pub(crate) struct DwI2cBusLock {
+    locked: AtomicBool,
+}
+
+impl DwI2cBusLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub(crate) fn lock(&self) {
+        while !self.try_lock() {
+            usleep(10);
+        }
+    }
+
+    pub(crate) fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Completion of a transfer, signalled from the irq handler
+///
+/// Waiters sleep on the kernel completion of osl, which is allocated.
+/// Outside Linux it is only allocated by the first transfer of owned
+/// msgs, the borrowed ops of `master_transfer_ops` poll a flag instead
+/// so they work without an allocator.
+pub(crate) struct DwI2cCompletion {
+    #[cfg(feature = "linux")]
+    inner: Arc<OslCompletion>,
+    #[cfg(feature = "arceos")]
+    inner: SpinLock<Option<Arc<OslCompletion>>>,
+    #[cfg(feature = "arceos")]
+    done: AtomicBool,
+}
+
+/// What a transfer waits on, taken by `DwI2cCompletion::reinit` while
+/// the irq handler can not complete, so `wait` needs no lock
+pub(crate) struct DwI2cWaiter {
+    #[cfg(feature = "arceos")]
+    completion: Option<Arc<OslCompletion>>,
+}
+
+impl DwI2cCompletion {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "linux")]
+            inner: OslCompletion::new().unwrap(),
+            #[cfg(feature = "arceos")]
+            inner: new_spinlock!(None),
+            #[cfg(feature = "arceos")]
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Rearm for the next transfer, which waits polling if `poll`
+    #[cfg(feature = "linux")]
+    pub(crate) fn reinit(&self, _poll: bool) -> DwI2cWaiter {
+        self.inner.reinit();
+        DwI2cWaiter {}
+    }
+
+    /// Rearm for the next transfer, which waits polling if `poll`
+    ///
+    /// A sleeping wait allocates the completion the first time, and
+    /// polls if that fails.
+    #[cfg(feature = "arceos")]
+    pub(crate) fn reinit(&self, poll: bool) -> DwI2cWaiter {
+        self.done.store(false, Ordering::Release);
+        let mut inner = self.inner.lock();
+        if !poll && inner.is_none() {
+            match OslCompletion::new() {
+                Ok(completion) => *inner = Some(completion),
+                Err(e) => log_warn!("{:?} while allocating the completion, polling", e),
+            }
+        }
+        if let Some(completion) = inner.as_ref() {
+            completion.reinit();
+        }
+        DwI2cWaiter {
+            completion: if poll { None } else { inner.clone() },
+        }
+    }
+
+    pub(crate) fn complete(&self) {
+        #[cfg(feature = "linux")]
+        self.inner.complete();
+        #[cfg(feature = "arceos")]
+        {
+            self.done.store(true, Ordering::Release);
+            if let Some(completion) = self.inner.lock().as_ref() {
+                completion.complete();
+            }
+        }
+    }
+
+    /// Wait for `complete`, `Errno::TimeOut` if the transfer takes too long
+    #[cfg(feature = "linux")]
+    pub(crate) fn wait(&self, _waiter: DwI2cWaiter) -> Result<()> {
+        self.inner.wait_for_completion_timeout(1)
+    }
+
+    /// Wait for `complete`, `Errno::TimeOut` if the transfer takes too long
+    #[cfg(feature = "arceos")]
+    pub(crate) fn wait(&self, waiter: DwI2cWaiter) -> Result<()> {
+        if let Some(completion) = waiter.completion {
+            return completion.wait_for_completion_timeout(1);
+        }
+
+        let deadline = osl::time::time_add_us(DW_I2C_XFER_TIMEOUT_US);
+        while !self.done.load(Ordering::Acquire) {
+            if osl::time::current_time() > deadline {
+                return to_error(Errno::TimeOut);
+            }
+            usleep(10);
+        }
+        Ok(())
     }
 }
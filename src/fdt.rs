@@ -0,0 +1,378 @@
+use osl::{
+    error::{to_error, Errno, Result},
+    driver::i2c::{I2cTiming, I2C_MAX_FAST_MODE_FREQ, I2C_MAX_STANDARD_MODE_FREQ},
+};
+
+use crate::I2cDwDriverConfig;
+
+/// Compatible string of the DW APB I2C binding
+const DW_I2C_COMPATIBLE: &str = "snps,designware-i2c";
+
+/// A device tree node, as seen through the FDT library of the platform
+pub trait I2cDwFdtNode {
+    /// Raw (big-endian) value of the property `name`, None if absent
+    fn property(&self, name: &str) -> Option<&[u8]>;
+    /// `#address-cells` and `#size-cells` of the parent node
+    fn parent_cells(&self) -> (usize, usize);
+    /// `#interrupt-cells` of the interrupt parent
+    fn interrupt_cells(&self) -> usize;
+    /// Interrupt number of `specifier`, the raw (big-endian) first
+    /// `interrupts` entry, as the interrupt parent translates it, None if
+    /// it is not valid for the interrupt parent
+    ///
+    /// A GIC for instance translates `<type number flags>` to `number + 32`
+    /// for SPIs and `number + 16` for PPIs.
+    fn translate_irq(&self, specifier: &[u8]) -> Option<usize>;
+    /// Rate in Hz of the first clock in `clocks`, resolved through its
+    /// phandle
+    fn clock_rate_hz(&self) -> Option<u32>;
+}
+
+/// A DW APB I2C controller described by a device tree node
+#[derive(Debug, Clone)]
+pub struct I2cDwFdtDevice {
+    /// Driver config from the bus timing properties
+    pub config: I2cDwDriverConfig,
+    /// Physical address of the registers, first `reg` entry
+    pub base_addr: usize,
+    /// First `interrupts` entry, translated by the interrupt parent
+    pub irq: usize,
+}
+
+impl I2cDwFdtDevice {
+    /// Parse `node`, with the defaults of Linux `i2c_parse_fw_timings`
+    /// for absent timing properties
+    ///
+    /// Disabled or incompatible nodes fail with `Errno::NoSuchDevice`,
+    /// malformed properties with `Errno::InvalidArgs` and
+    /// `snps,clk-freq-optimized`, whose SCL count tuning is not
+    /// implemented, with `Errno::NotSupported`.
+    pub fn parse(node: &dyn I2cDwFdtNode) -> Result<Self> {
+        let compatible = node.property("compatible").unwrap_or(&[]);
+        if !compatible
+            .split(|c| *c == 0)
+            .any(|c| c == DW_I2C_COMPATIBLE.as_bytes())
+        {
+            log_err!("fdt node is not {} compatible", DW_I2C_COMPATIBLE);
+            return to_error(Errno::NoSuchDevice);
+        }
+
+        if let Some(status) = node.property("status") {
+            if status != b"okay\0" && status != b"ok\0" {
+                log_info!("fdt node disabled");
+                return to_error(Errno::NoSuchDevice);
+            }
+        }
+
+        let clk_rate_khz = match node.clock_rate_hz() {
+            Some(rate) if rate >= 1000 => rate / 1000,
+            _ => {
+                log_err!("fdt node without a valid ic clock");
+                return to_error(Errno::InvalidArgs);
+            }
+        };
+
+        // Linux tunes the SCL counts for blocks synthesized with
+        // IC_CLK_FREQ_OPTIMIZATION, the plain counts would miss the bus speed
+        if node.property("snps,clk-freq-optimized").is_some() {
+            log_err!("snps,clk-freq-optimized is not supported");
+            return to_error(Errno::NotSupported);
+        }
+
+        let mut config = I2cDwDriverConfig::new(Self::parse_timing(node)?, clk_rate_khz);
+        if let Some(bus_capacitance_pf) = read_u32(node, "snps,bus-capacitance-pf")? {
+            config = config.with_bus_capacitance_pf(bus_capacitance_pf)?;
+        }
+
+        Ok(Self {
+            config,
+            base_addr: Self::parse_reg(node)?,
+            irq: Self::parse_irq(node)?,
+        })
+    }
+
+    /// Same defaults as Linux `i2c_parse_fw_timings` with `use_defaults`
+    fn parse_timing(node: &dyn I2cDwFdtNode) -> Result<I2cTiming> {
+        let bus_freq_hz =
+            read_u32(node, "clock-frequency")?.unwrap_or(I2C_MAX_STANDARD_MODE_FREQ);
+        if bus_freq_hz == 0 {
+            log_err!("invalid clock-frequency 0");
+            return to_error(Errno::InvalidArgs);
+        }
+
+        let scl_rise_ns = read_u32(node, "i2c-scl-rising-time-ns")?.unwrap_or(
+            if bus_freq_hz <= I2C_MAX_STANDARD_MODE_FREQ {
+                1000
+            } else if bus_freq_hz <= I2C_MAX_FAST_MODE_FREQ {
+                300
+            } else {
+                120
+            },
+        );
+        let scl_fall_ns = read_u32(node, "i2c-scl-falling-time-ns")?.unwrap_or(
+            if bus_freq_hz <= I2C_MAX_FAST_MODE_FREQ { 300 } else { 120 },
+        );
+        let scl_int_delay_ns = read_u32(node, "i2c-scl-internal-delay-ns")?.unwrap_or(0);
+        let sda_fall_ns = read_u32(node, "i2c-sda-falling-time-ns")?.unwrap_or(scl_fall_ns);
+        let sda_hold_ns = read_u32(node, "i2c-sda-hold-time-ns")?.unwrap_or(0);
+        let digital_filter_width_ns = read_u32(node, "i2c-digital-filter-width-ns")?.unwrap_or(0);
+        let analog_filter_cutoff_freq_hz =
+            read_u32(node, "i2c-analog-filter-cutoff-frequency")?.unwrap_or(0);
+
+        Ok(I2cTiming::new(
+            bus_freq_hz,
+            scl_rise_ns,
+            scl_fall_ns,
+            scl_int_delay_ns,
+            sda_fall_ns,
+            sda_hold_ns,
+            digital_filter_width_ns,
+            analog_filter_cutoff_freq_hz,
+        ))
+    }
+
+    fn parse_reg(node: &dyn I2cDwFdtNode) -> Result<usize> {
+        let (address_cells, size_cells) = node.parent_cells();
+        let reg = node.property("reg").unwrap_or(&[]);
+        if address_cells == 0 || address_cells > 2 || reg.len() < (address_cells + size_cells) * 4 {
+            log_err!("invalid fdt reg, {} address cells", address_cells);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        let base_addr = read_cells(&reg[..address_cells * 4]);
+        if base_addr == 0 || base_addr > usize::MAX as u64 {
+            log_err!("invalid fdt reg base 0x{:x}", base_addr);
+            return to_error(Errno::InvalidArgs);
+        }
+        Ok(base_addr as usize)
+    }
+
+    fn parse_irq(node: &dyn I2cDwFdtNode) -> Result<usize> {
+        let interrupt_cells = node.interrupt_cells();
+        let interrupts = node.property("interrupts").unwrap_or(&[]);
+        if interrupt_cells == 0 || interrupts.len() < interrupt_cells * 4 {
+            log_err!("invalid fdt interrupts, {} interrupt cells", interrupt_cells);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        match node.translate_irq(&interrupts[..interrupt_cells * 4]) {
+            Some(irq) => Ok(irq),
+            None => {
+                log_err!("invalid fdt interrupts specifier");
+                to_error(Errno::InvalidArgs)
+            }
+        }
+    }
+}
+
+/// Read the u32 property `name`, None if absent
+fn read_u32(node: &dyn I2cDwFdtNode, name: &str) -> Result<Option<u32>> {
+    match node.property(name) {
+        None => Ok(None),
+        Some(value) if value.len() == 4 => Ok(Some(read_cells(value) as u32)),
+        Some(value) => {
+            log_err!("fdt property {} has {} bytes, expect a u32", name, value.len());
+            to_error(Errno::InvalidArgs)
+        }
+    }
+}
+
+/// Join big-endian cells into a number
+fn read_cells(cells: &[u8]) -> u64 {
+    cells.iter().fold(0, |val, byte| (val << 8) | u64::from(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPATIBLE: &[u8] = b"vendor,i2c\0snps,designware-i2c\0";
+    const REG: &[u8] = &[0, 0, 0, 0, 0xfe, 0x20, 0x50, 0x00, 0, 0, 0, 0, 0, 0, 0x10, 0x00];
+    const INTERRUPTS: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 0x2a, 0, 0, 0, 4];
+
+    struct MockNode {
+        props: &'static [(&'static str, &'static [u8])],
+        parent_cells: (usize, usize),
+        interrupt_cells: usize,
+        clock_rate_hz: Option<u32>,
+    }
+
+    impl MockNode {
+        fn new(props: &'static [(&'static str, &'static [u8])]) -> Self {
+            Self {
+                props,
+                parent_cells: (2, 2),
+                interrupt_cells: 3,
+                clock_rate_hz: Some(100_000_000),
+            }
+        }
+    }
+
+    impl I2cDwFdtNode for MockNode {
+        fn property(&self, name: &str) -> Option<&[u8]> {
+            // the base properties, unless the test overrides them
+            let base: [(&str, &[u8]); 3] =
+                [("compatible", COMPATIBLE), ("reg", REG), ("interrupts", INTERRUPTS)];
+            self.props
+                .iter()
+                .chain(base.iter())
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| *value)
+        }
+
+        fn parent_cells(&self) -> (usize, usize) {
+            self.parent_cells
+        }
+
+        fn interrupt_cells(&self) -> usize {
+            self.interrupt_cells
+        }
+
+        fn clock_rate_hz(&self) -> Option<u32> {
+            self.clock_rate_hz
+        }
+
+        // GIC: <type number flags>, SPIs start at 32
+        fn translate_irq(&self, specifier: &[u8]) -> Option<usize> {
+            if specifier.len() != 12 || read_cells(&specifier[..4]) != 0 {
+                return None;
+            }
+            Some(read_cells(&specifier[4..8]) as usize + 32)
+        }
+    }
+
+    fn timing(device: &I2cDwFdtDevice) -> (u32, u32, u32, u32, u32) {
+        let timing = &device.config.timing;
+        (
+            timing.get_bus_freq_hz(),
+            timing.get_scl_rise_ns(),
+            timing.get_scl_fall_ns(),
+            timing.get_sda_fall_ns(),
+            timing.get_sda_hold_ns(),
+        )
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let device = I2cDwFdtDevice::parse(&MockNode::new(&[])).unwrap();
+        assert_eq!(device.base_addr, 0xfe20_5000);
+        assert_eq!(device.irq, 0x2a + 32);
+        assert_eq!(device.config.clk_rate_khz, 100_000);
+        assert_eq!(device.config.bus_capacitance_pf, 400);
+        assert_eq!(timing(&device), (100_000, 1000, 300, 300, 0));
+    }
+
+    #[test]
+    fn parse_defaults_by_speed() {
+        let node = MockNode::new(&[("clock-frequency", &[0, 0x06, 0x1a, 0x80])]);
+        let device = I2cDwFdtDevice::parse(&node).unwrap();
+        assert_eq!(timing(&device), (400_000, 300, 300, 300, 0));
+
+        let node = MockNode::new(&[("clock-frequency", &[0, 0x0f, 0x42, 0x40])]);
+        let device = I2cDwFdtDevice::parse(&node).unwrap();
+        assert_eq!(timing(&device), (1_000_000, 120, 120, 120, 0));
+    }
+
+    #[test]
+    fn parse_timing_overrides() {
+        let node = MockNode::new(&[
+            ("clock-frequency", &[0, 0x06, 0x1a, 0x80]),
+            ("i2c-scl-rising-time-ns", &[0, 0, 0, 200]),
+            ("i2c-scl-falling-time-ns", &[0, 0, 0, 100]),
+            ("i2c-sda-falling-time-ns", &[0, 0, 0, 90]),
+            ("i2c-sda-hold-time-ns", &[0, 0, 0x01, 0x2c]),
+        ]);
+        let device = I2cDwFdtDevice::parse(&node).unwrap();
+        assert_eq!(timing(&device), (400_000, 200, 100, 90, 300));
+    }
+
+    #[test]
+    fn parse_sda_fall_follows_scl_fall() {
+        let node = MockNode::new(&[("i2c-scl-falling-time-ns", &[0, 0, 0, 100])]);
+        let device = I2cDwFdtDevice::parse(&node).unwrap();
+        assert_eq!(timing(&device), (100_000, 1000, 100, 100, 0));
+    }
+
+    #[test]
+    fn parse_bus_capacitance() {
+        let node = MockNode::new(&[("snps,bus-capacitance-pf", &[0, 0, 0, 100])]);
+        let device = I2cDwFdtDevice::parse(&node).unwrap();
+        assert_eq!(device.config.bus_capacitance_pf, 100);
+
+        let node = MockNode::new(&[("snps,bus-capacitance-pf", &[0, 0, 0, 200])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+    }
+
+    #[test]
+    fn parse_rejects_clk_freq_optimized() {
+        let node = MockNode::new(&[("snps,clk-freq-optimized", &[])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::NotSupported)));
+    }
+
+    #[test]
+    fn parse_rejects_incompatible_or_disabled() {
+        let node = MockNode::new(&[("compatible", b"vendor,i2c\0")]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::NoSuchDevice)));
+
+        let node = MockNode::new(&[("status", b"disabled\0")]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::NoSuchDevice)));
+
+        let node = MockNode::new(&[("status", b"okay\0")]);
+        assert!(I2cDwFdtDevice::parse(&node).is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_bad_clock() {
+        let mut node = MockNode::new(&[]);
+        node.clock_rate_hz = None;
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        node.clock_rate_hz = Some(999);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        let node = MockNode::new(&[("clock-frequency", &[0, 0, 0, 0])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_u32() {
+        let node = MockNode::new(&[("i2c-sda-hold-time-ns", &[0, 0x10])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+    }
+
+    #[test]
+    fn parse_reg_cells() {
+        let mut node = MockNode::new(&[("reg", &[0xfe, 0x20, 0x50, 0x00, 0, 0, 0x10, 0x00])]);
+        node.parent_cells = (1, 1);
+        assert_eq!(I2cDwFdtDevice::parse(&node).unwrap().base_addr, 0xfe20_5000);
+
+        node.parent_cells = (0, 1);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        // shorter than the address and size cells
+        node.parent_cells = (2, 2);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        let mut node = MockNode::new(&[("reg", &[0, 0, 0, 0, 0, 0, 0x10, 0x00])]);
+        node.parent_cells = (1, 1);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+    }
+
+    #[test]
+    fn parse_irq_translation() {
+        // a PPI, which the mock interrupt parent does not translate
+        let node = MockNode::new(&[("interrupts", &[0, 0, 0, 1, 0, 0, 0, 0x0d, 0, 0, 0, 4])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        let mut node = MockNode::new(&[]);
+        node.interrupt_cells = 0;
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        // fewer cells than the interrupt parent asks for
+        node.interrupt_cells = 4;
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+
+        let node = MockNode::new(&[("interrupts", &[])]);
+        assert!(matches!(I2cDwFdtDevice::parse(&node), Err(Errno::InvalidArgs)));
+    }
+}
@@ -0,0 +1,141 @@
+use osl::driver::i2c::I2cSpeedMode;
+
+use tock_registers::LocalRegisterCopy;
+
+use crate::registers::{IC_COMP_PARAM_1, DW_IC_SDA_HOLD_MIN_VERS};
+
+/// What the DW APB I2C block was synthesized with, read at probe time
+/// from IC_COMP_PARAM_1, IC_COMP_VERSION and IC_CON
+#[derive(Debug, Clone, Copy)]
+pub struct DwI2cHwParams {
+    /// Raw IC_COMP_VERSION, e.g. 0x3230312a for 2.01*
+    pub comp_version: u32,
+    /// `comp_version` decoded, see `version`
+    version: Option<[u8; 5]>,
+    /// Fastest speed mode the block supports
+    pub max_speed_mode: I2cSpeedMode,
+    /// TX FIFO depth in entries
+    pub tx_fifo_depth: u32,
+    /// RX FIFO depth in entries
+    pub rx_fifo_depth: u32,
+    /// APB data width in bits, 8, 16 or 32
+    pub apb_data_width: u32,
+    /// IC_COMP_PARAM_1 holds the synthesis parameters, the fields
+    /// below are only meaningful if set
+    pub encoded_params: bool,
+    /// DMA handshaking interface present
+    pub has_dma: bool,
+    /// All interrupts combined on one output
+    pub intr_io: bool,
+    /// SCL counts are hardcoded, IC_*_SCL_*CNT are read-only
+    pub hc_count_values: bool,
+    /// IC_SDA_HOLD is present, from version 1.11a on
+    pub sda_hold: bool,
+    /// SDA/SCL stuck low recovery is enabled in IC_CON
+    pub bus_clear: bool,
+}
+
+impl DwI2cHwParams {
+    pub(crate) fn new(
+        com_param_1: LocalRegisterCopy<u32, IC_COMP_PARAM_1::Register>,
+        comp_version: u32,
+        bus_clear: bool,
+    ) -> Self {
+        let max_speed_mode = match com_param_1.read(IC_COMP_PARAM_1::MAX_SPEED_MODE) {
+            0b11 => I2cSpeedMode::HighSpeedMode,
+            // fast mode counts also drive Fast Mode Plus
+            0b10 => I2cSpeedMode::FastPlusMode,
+            _ => I2cSpeedMode::StandMode,
+        };
+        let apb_data_width = match com_param_1.read(IC_COMP_PARAM_1::APB_DATA_WIDTH) {
+            0b00 => 8,
+            0b01 => 16,
+            _ => 32,
+        };
+
+        Self {
+            comp_version,
+            version: Self::decode_version(comp_version),
+            max_speed_mode,
+            tx_fifo_depth: com_param_1.read(IC_COMP_PARAM_1::TX_BUFFER_DEPTH) + 1,
+            rx_fifo_depth: com_param_1.read(IC_COMP_PARAM_1::RX_BUFFER_DEPTH) + 1,
+            apb_data_width,
+            encoded_params: com_param_1.is_set(IC_COMP_PARAM_1::ADD_ENCODED_PARAMS),
+            has_dma: com_param_1.is_set(IC_COMP_PARAM_1::HAS_DMA),
+            intr_io: com_param_1.is_set(IC_COMP_PARAM_1::INTR_IO),
+            hc_count_values: com_param_1.is_set(IC_COMP_PARAM_1::HC_COUNT_VALUES),
+            sda_hold: comp_version >= DW_IC_SDA_HOLD_MIN_VERS,
+            bus_clear,
+        }
+    }
+
+    /// IC_COMP_VERSION decoded, e.g. "2.01a", None if it is not three
+    /// ASCII digits and a letter or `*`
+    pub fn version(&self) -> Option<&str> {
+        self.version
+            .as_ref()
+            .and_then(|version| core::str::from_utf8(version).ok())
+    }
+
+    /// Whether the block can run the bus in `mode`
+    pub fn supports_speed(&self, mode: I2cSpeedMode) -> bool {
+        speed_rank(mode) <= speed_rank(self.max_speed_mode)
+    }
+
+    fn decode_version(comp_version: u32) -> Option<[u8; 5]> {
+        let [major, minor, patch, letter] = comp_version.to_be_bytes();
+        if ![major, minor, patch].iter().all(u8::is_ascii_digit)
+            || !(letter.is_ascii_alphabetic() || letter == b'*')
+        {
+            return None;
+        }
+        Some([major, b'.', minor, patch, letter.to_ascii_lowercase()])
+    }
+}
+
+fn speed_rank(mode: I2cSpeedMode) -> u8 {
+    match mode {
+        I2cSpeedMode::StandMode => 0,
+        I2cSpeedMode::FastMode => 1,
+        I2cSpeedMode::FastPlusMode => 2,
+        I2cSpeedMode::HighSpeedMode => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn com_param_1(max_speed_mode: u32) -> LocalRegisterCopy<u32, IC_COMP_PARAM_1::Register> {
+        LocalRegisterCopy::new(max_speed_mode << 2)
+    }
+
+    #[test]
+    fn version_decodes_star() {
+        let hw_params = DwI2cHwParams::new(com_param_1(0b01), 0x3230312a, false);
+        assert_eq!(hw_params.version(), Some("2.01*"));
+    }
+
+    #[test]
+    fn version_decodes_letter() {
+        let hw_params = DwI2cHwParams::new(com_param_1(0b01), 0x3131314a, false);
+        assert_eq!(hw_params.version(), Some("1.11j"));
+    }
+
+    #[test]
+    fn version_rejects_invalid_bytes() {
+        for comp_version in [0, 0x32303120, 0x3230782a, 0xffffffff] {
+            let hw_params = DwI2cHwParams::new(com_param_1(0b01), comp_version, false);
+            assert_eq!(hw_params.version(), None, "0x{:x}", comp_version);
+        }
+    }
+
+    #[test]
+    fn max_speed_mode_mapping() {
+        let max_speed_mode = |bits| DwI2cHwParams::new(com_param_1(bits), 0, false).max_speed_mode;
+        assert!(matches!(max_speed_mode(0b11), I2cSpeedMode::HighSpeedMode));
+        assert!(matches!(max_speed_mode(0b10), I2cSpeedMode::FastPlusMode));
+        assert!(matches!(max_speed_mode(0b01), I2cSpeedMode::StandMode));
+        assert!(matches!(max_speed_mode(0b00), I2cSpeedMode::StandMode));
+    }
+}
@@ -8,8 +8,10 @@
 //!
 //! // Create and setup device driver
 //! let driver_config = I2cDwDriverConfig::new(timing, clk_rate_khz); 
-//! let mut i2c_master_driver = I2cDwMasterDriver::new(driver_config, reg_base); 
-//! i2c_master_driver.setup();
+//! // SAFETY: reg_base maps the controller registers for the program duration
+//! let mut i2c_master_driver =
+//!     unsafe { I2cDwMasterDriver::try_new(driver_config, reg_base) }?;
+//! i2c_master_driver.setup()?;
 //!
 //! // enable device irq hanlder on your OS with  driver
 //! os_regist_irq(irq_num, i2c_master_driver.irq_handler())
@@ -49,23 +51,88 @@ use tock_registers::{
 
 pub(crate) mod common;
 pub(crate) mod core;
+#[cfg(feature = "fdt")]
+mod fdt;
+mod hw_params;
 mod master;
+mod operation;
+mod pm;
+mod quirks;
 pub(crate) mod registers;
+mod semaphore;
+mod slave;
+mod smbus;
 
 use crate::{
-    common::DwI2cSclLHCnt,
+    common::{DwI2cSclLHCnt, DwI2cSmbusCnt},
     core::*,
     registers::*,
 };
 
 const __LOG_PREFIX: &[u8] = b"i2c-designware\0";
 
+/// SMBus clock low extension limits
+#[derive(Debug, Clone, Copy)]
+pub struct I2cDwSmbusTimeouts {
+    /// tLOW:SEXT in ms, max cumulative clock low extension of a
+    /// target during one message
+    pub clk_low_sext_ms: u32,
+    /// tLOW:MEXT in ms, max cumulative clock low extension of the
+    /// master during one byte
+    pub clk_low_mext_ms: u32,
+}
+
+impl Default for I2cDwSmbusTimeouts {
+    /// Limits from the SMBus specification
+    fn default() -> Self {
+        Self {
+            clk_low_sext_ms: 25,
+            clk_low_mext_ms: 10,
+        }
+    }
+}
+
+/// IC_RX_TL policy of the master, see `I2cDwFifoThresholds`
+///
+/// RX_FULL fires once more than IC_RX_TL bytes are in the RX FIFO, so
+/// IC_RX_TL is always lowered while fewer read bytes are outstanding,
+/// e.g. at the tail of a read or for an SMBus block length byte.
+#[derive(Debug, Clone, Copy)]
+pub enum I2cDwRxThreshold {
+    /// IC_RX_TL at most this value, 0 interrupts on every received byte
+    Fixed(u32),
+    /// IC_RX_TL follows the read in progress: RX_FULL fires once all
+    /// outstanding read bytes are in, at most half the RX FIFO so the
+    /// next read commands are queued meanwhile
+    Adaptive,
+}
+
+impl Default for I2cDwRxThreshold {
+    /// Interrupt on every received byte
+    fn default() -> Self {
+        Self::Fixed(0)
+    }
+}
+
+/// FIFO thresholds of the master, trading interrupts for FIFO slack
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I2cDwFifoThresholds {
+    /// IC_TX_TL, TX_EMPTY fires at or below this many TX FIFO entries.
+    /// None for half the TX FIFO (the default)
+    pub tx_tl: Option<u32>,
+    /// IC_RX_TL policy
+    pub rx: I2cDwRxThreshold,
+}
+
 /// I2cDwDriverConfig
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct I2cDwDriverConfig {
     timing: I2cTiming,
     clk_rate_khz: u32,
+    smbus_timeouts: Option<I2cDwSmbusTimeouts>,
+    bus_capacitance_pf: u32,
+    fifo_thresholds: I2cDwFifoThresholds,
 }
 
 impl I2cDwDriverConfig {
@@ -74,34 +141,107 @@ impl I2cDwDriverConfig {
         Self {
             timing,
             clk_rate_khz,
+            smbus_timeouts: None,
+            bus_capacitance_pf: 400,
+            fifo_thresholds: I2cDwFifoThresholds::default(),
+        }
+    }
+
+    /// Enable SMBus compliance mode
+    ///
+    /// Clock low extensions beyond `timeouts` abort the transfer with
+    /// `Errno::Protocol` and reset the bus, a transfer which does not
+    /// complete at all fails with `Errno::TimeOut`.
+    pub fn with_smbus_timeouts(mut self, timeouts: I2cDwSmbusTimeouts) -> Self {
+        self.smbus_timeouts = Some(timeouts);
+        self
+    }
+
+    /// Set the bus capacitance for the High Speed mode timings, 100 or
+    /// 400 pF (the default)
+    pub fn with_bus_capacitance_pf(mut self, bus_capacitance_pf: u32) -> Result<Self> {
+        if bus_capacitance_pf != 100 && bus_capacitance_pf != 400 {
+            log_err!("unsupported bus capacitance {} pF", bus_capacitance_pf);
+            return to_error(Errno::InvalidArgs);
         }
+        self.bus_capacitance_pf = bus_capacitance_pf;
+        Ok(self)
+    }
+
+    /// Set the master FIFO thresholds, values beyond the FIFO depth are
+    /// lowered to it at setup
+    pub fn with_fifo_thresholds(mut self, thresholds: I2cDwFifoThresholds) -> Self {
+        self.fifo_thresholds = thresholds;
+        self
     }
 }
 
-pub use crate::master::I2cDwMasterDriver;
+#[cfg(feature = "fdt")]
+pub use crate::fdt::{I2cDwFdtDevice, I2cDwFdtNode};
+pub use crate::hw_params::DwI2cHwParams;
+pub use crate::master::{I2cDeviceId, I2cDwBusGuard, I2cDwMasterDriver, I2cDwRetryPolicy};
+pub use crate::operation::Operation;
+pub use crate::pm::I2cDwPlatformHooks;
+pub use crate::quirks::{I2cDwAdapterQuirks, I2C_DW_XFER_MAX_MSGS};
+pub use crate::registers::{I2cDwMmio, I2cDwRegAccess};
+pub use crate::semaphore::I2cDwBusSemaphore;
+pub use crate::slave::{
+    I2cDwSlaveDriver, I2cDwSlaveEvent, I2cDwSlaveHandler, I2cDwTargetAddrMatch,
+};
+pub use crate::smbus::{SmbusAlertHandler, SmbusHostNotifyHandler, SMBUS_ALERT_MAX_HANDLERS};
+
+/// The bus timing computed from an `I2cTiming` and the ic_clk rate
+///
+/// Computed as a whole, so a failed reconfiguration leaves the timing
+/// in use untouched.
+#[derive(Clone)]
+pub(crate) struct DwI2cBusTiming {
+    /// ic_clk rate in kHz
+    pub(crate) clk_rate_khz: u32,
+    /// From `I2cTiming`, 0 for the defaults
+    pub(crate) scl_fall_ns: u32,
+    pub(crate) sda_fall_ns: u32,
+    pub(crate) sda_hold_ns: u32,
+    /// Corrected bus_freq_hz
+    pub(crate) bus_freq_hz: u32,
+    /// I2c SpeedMode
+    pub(crate) speed_mode: I2cSpeedMode,
+    /// Corrected sda_hold_time
+    pub(crate) sda_hold_time: Option<LocalRegisterCopy::<u32,IC_SDA_HOLD::Register>>,
+    /// SMBus timeout counts, None if SMBus compliance mode is off
+    pub(crate) smbus_cnt: Option<DwI2cSmbusCnt>,
+}
+
+impl DwI2cBusTiming {
+    pub(crate) fn new(timing: &I2cTiming, clk_rate_khz: u32) -> Self {
+        Self {
+            clk_rate_khz,
+            scl_fall_ns: timing.get_scl_fall_ns(),
+            sda_fall_ns: timing.get_sda_fall_ns(),
+            sda_hold_ns: timing.get_sda_hold_ns(),
+            bus_freq_hz: timing.get_bus_freq_hz(),
+            speed_mode: I2cSpeedMode::StandMode,
+            sda_hold_time: None,
+            smbus_cnt: None,
+        }
+    }
+}
 
 /// The I2cDesignware Core Driver
 #[allow(dead_code)]
-pub(crate) struct I2cDwCoreDriver {
+pub(crate) struct I2cDwCoreDriver<A: I2cDwRegAccess = I2cDwMmio> {
     /// I2c Registers
-    pub(crate) regs: DwApbI2cRegistersRef,
+    pub(crate) regs: DwApbI2cRegistersRef<A>,
     /// Config From external
     pub(crate) ext_config: I2cDwDriverConfig,
-    /// Corrected bus_freq_hz
-    pub(crate) bus_freq_hz: u32,
-    /// Corrected sda_hold_time
-    pub(crate) sda_hold_time: Option<LocalRegisterCopy::<u32,IC_SDA_HOLD::Register>>,
 
     /// I2c functionality
     pub(crate) functionality: I2cFuncFlags,
 
-    /// I2c SpeedMode
-    speed_mode: I2cSpeedMode,
+    /// Synthesis parameters, None until probed
+    pub(crate) hw_params: Option<DwI2cHwParams>,
 }
 
-unsafe impl Sync for I2cDwCoreDriver {}
-unsafe impl Send for I2cDwCoreDriver {}
-
 const I2C_DESIGNWARE_SUPPORT_SPEED: [u32; 4] = [
     I2C_MAX_STANDARD_MODE_FREQ,
     I2C_MAX_FAST_MODE_FREQ,
@@ -110,58 +250,104 @@ const I2C_DESIGNWARE_SUPPORT_SPEED: [u32; 4] = [
 ];
 
 #[allow(dead_code)]
-impl I2cDwCoreDriver {
-    pub(crate) fn new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Self {
+impl<A: I2cDwRegAccess> I2cDwCoreDriver<A> {
+    pub(crate) fn new(config: I2cDwDriverConfig, backend: A) -> Self {
         Self {
             ext_config: config,
-            regs: DwApbI2cRegistersRef::new(base_addr),
-            bus_freq_hz: 0,
-            sda_hold_time: None,
+            regs: DwApbI2cRegistersRef::new(backend),
             functionality: DW_I2C_DEFAULT_FUNCTIONALITY,
-            speed_mode: I2cSpeedMode::StandMode,
+            hw_params: None,
         }
     }
 
-    pub(crate) fn speed_check(&mut self) -> Result<()> {
-        let bus_freq_hz = self.ext_config.timing.get_bus_freq_hz();
+    pub(crate) fn speed_check(&self, bus: &mut DwI2cBusTiming) -> Result<()> {
+        let bus_freq_hz = bus.bus_freq_hz;
 
         if !I2C_DESIGNWARE_SUPPORT_SPEED.contains(&bus_freq_hz) {
             log_err!("{bus_freq_hz} Hz is unsupported, only 100kHz, 400kHz, 1MHz and 3.4MHz are supported");
             return to_error(Errno::InvalidArgs);
         }
-        self.bus_freq_hz = bus_freq_hz;
-
         if !self
             .regs
-            .IC_COMP_PARAM_1
+            .IC_COMP_PARAM_1()
             .is_set(IC_COMP_PARAM_1::MAX_SPEED_MODE)
-            && self.bus_freq_hz == I2C_MAX_HIGH_SPEED_MODE_FREQ
+            && bus.bus_freq_hz == I2C_MAX_HIGH_SPEED_MODE_FREQ
         {
             log_err!("High Speed not supported! Fall back to fast mode");
-            self.bus_freq_hz = I2C_MAX_FAST_MODE_FREQ;
+            bus.bus_freq_hz = I2C_MAX_FAST_MODE_FREQ;
         }
 
-        self.speed_mode = I2cSpeedMode::from_bus_freq(self.bus_freq_hz);
+        bus.speed_mode = I2cSpeedMode::from_bus_freq(bus.bus_freq_hz);
         Ok(())
     }
 
     pub(crate) fn com_type_check(&mut self) -> Result<()> {
-        let com_type = self.regs.IC_COMP_TYPE.get();
-        if com_type == DW_IC_COMP_TYPE_VALUE {
-            log_info!("com_type check Ok");
+        // probe with plain 32-bit accesses, then pick the access mode
+        // matching what the bus made of IC_COMP_TYPE
+        self.regs.set_access(DwRegAccess::Native32);
+        let com_type = self.regs.IC_COMP_TYPE().get();
+        let access = if com_type == DW_IC_COMP_TYPE_VALUE {
+            DwRegAccess::Native32
         } else if com_type == DW_IC_COMP_TYPE_VALUE & 0x0000ffff {
-            log_err!("com_type check Failed, not support 16 bit system ");
-            return to_error(Errno::NoSuchDevice);
-        } else if com_type == DW_IC_COMP_TYPE_VALUE.to_be() {
-            log_err!("com_type check Failed, not support BE system ");
-            return to_error(Errno::NoSuchDevice);
+            DwRegAccess::Split16
+        } else if com_type == DW_IC_COMP_TYPE_VALUE.swap_bytes() {
+            DwRegAccess::Swab32
         } else {
             log_err!(
                 "com_type check failed, Unknown Synopsys component type: {:x}",
                 com_type
             );
             return to_error(Errno::NoSuchDevice);
+        };
+
+        self.regs.set_access(access);
+        let com_type = self.regs.IC_COMP_TYPE().get();
+        if com_type != DW_IC_COMP_TYPE_VALUE {
+            log_err!("com_type check failed with {:?} access: {:x}", access, com_type);
+            self.regs.set_access(DwRegAccess::Native32);
+            return to_error(Errno::NoSuchDevice);
+        }
+        log_info!("com_type check Ok, {:?} access", access);
+        Ok(())
+    }
+
+    /// Check IC_COMP_TYPE with the access mode picked by `probe`, e.g.
+    /// after the block may have lost power
+    pub(crate) fn com_type_verify(&self) -> Result<()> {
+        let com_type = self.regs.IC_COMP_TYPE().get();
+        if com_type != DW_IC_COMP_TYPE_VALUE {
+            log_err!("com_type check failed, Unknown Synopsys component type: {:x}", com_type);
+            return to_error(Errno::NoSuchDevice);
+        }
+        Ok(())
+    }
+
+    /// Check `regs` is a DW APB I2C block before anything is written:
+    /// IC_COMP_TYPE must match and IC_COMP_PARAM_1 must read sanely
+    pub(crate) fn probe(&mut self) -> Result<()> {
+        self.com_type_check()?;
+
+        let com_param_1 = self.regs.IC_COMP_PARAM_1().get();
+        // an unclocked or unmapped block typically reads all ones
+        if com_param_1 == u32::MAX {
+            log_err!("probe failed, IC_COMP_PARAM_1 reads {:x}", com_param_1);
+            return to_error(Errno::NoSuchDevice);
+        }
+
+        let hw_params = DwI2cHwParams::new(
+            LocalRegisterCopy::new(com_param_1),
+            self.regs.IC_COMP_VERSION().get(),
+            self.regs.IC_CON().is_set(IC_CON::BUS_CLEAR_FEATURE_CTRL),
+        );
+        log_info!(
+            "probe Ok, version {} IC_COMP_PARAM_1 {:x}",
+            hw_params.version().unwrap_or("unknown"),
+            com_param_1
+        );
+        if !hw_params.encoded_params {
+            log_warn!("IC_COMP_PARAM_1 has no encoded params, assuming its defaults");
         }
+        self.hw_params = Some(hw_params);
         Ok(())
     }
 
@@ -170,18 +356,27 @@ impl I2cDwCoreDriver {
         self.functionality |= functionality;
     }
 
+    #[inline]
+    pub(crate) fn ic_device_id(&self) -> u32 {
+        self.regs.IC_DEVICE_ID().read(IC_DEVICE_ID::DEVICE_ID)
+    }
+
     #[inline]
     pub(crate) fn ic_comp_param_1(&self) -> LocalRegisterCopy<u32, IC_COMP_PARAM_1::Register> {
-        self.regs.IC_COMP_PARAM_1.extract()
+        self.regs.IC_COMP_PARAM_1().extract()
     }
 
     #[inline]
     pub(crate) fn ic_con(&self) -> LocalRegisterCopy<u32, IC_CON::Register> {
-        self.regs.IC_CON.extract()
+        self.regs.IC_CON().extract()
     }
 
-    pub(crate) fn cfg_init_speed(&self, cfg: &mut LocalRegisterCopy<u32, IC_CON::Register>) {
-        match self.speed_mode {
+    pub(crate) fn cfg_init_speed(
+        &self,
+        bus: &DwI2cBusTiming,
+        cfg: &mut LocalRegisterCopy<u32, IC_CON::Register>,
+    ) {
+        match bus.speed_mode {
             I2cSpeedMode::StandMode => cfg.modify(IC_CON::SPEED.val(0b01)),
             I2cSpeedMode::HighSpeedMode => cfg.modify(IC_CON::SPEED.val(0b11)),
             _ => cfg.modify(IC_CON::SPEED.val(0b10)),
@@ -190,48 +385,48 @@ impl I2cDwCoreDriver {
 
     #[inline]
     pub(crate) fn write_ic_con(&self, cfg: &LocalRegisterCopy<u32, IC_CON::Register>) {
-        self.regs.IC_CON.set(cfg.get());
+        self.regs.IC_CON().set(cfg.get());
     }
 
     #[inline]
     pub(crate) fn enable_10bitaddr(&self, enable: bool) {
         if enable {
             self.regs
-                .IC_CON
+                .IC_CON()
                 .modify(IC_CON::IC_10BITADDR_MASTER.val(0b1));
         } else {
             self.regs
-                .IC_CON
+                .IC_CON()
                 .modify(IC_CON::IC_10BITADDR_MASTER.val(0b0));
         }
     }
 
-    pub(crate) fn write_sda_hold_time(&self) {
-        if self.sda_hold_time.is_some() {
+    pub(crate) fn write_sda_hold_time(&self, bus: &DwI2cBusTiming) {
+        if bus.sda_hold_time.is_some() {
             self.regs
-                .IC_SDA_HOLD
-                .set(self.sda_hold_time.as_ref().unwrap().get());
-        log_info!("write sda_hold_time {}", self.sda_hold_time.as_ref().unwrap().get());
+                .IC_SDA_HOLD()
+                .set(bus.sda_hold_time.as_ref().unwrap().get());
+        log_info!("write sda_hold_time {}", bus.sda_hold_time.as_ref().unwrap().get());
         }
     }
 
-    pub(crate) fn sda_hold_time_init(&mut self) -> Result<()> {
-        let comp_ver = self.regs.IC_COMP_VERSION.get();
-        let ext_sda_hold_ns = self.ext_config.timing.get_sda_hold_ns();
+    pub(crate) fn sda_hold_time_init(&self, bus: &mut DwI2cBusTiming) -> Result<()> {
+        let comp_ver = self.regs.IC_COMP_VERSION().get();
+        let ext_sda_hold_ns = bus.sda_hold_ns;
 
         if comp_ver < DW_IC_SDA_HOLD_MIN_VERS {
             log_warn!("Hardware too old to adjust SDA hold time.");
-            self.sda_hold_time = None;
+            bus.sda_hold_time = None;
             return Ok(());
         }
 
         if ext_sda_hold_ns == 0 {
-            let sda_hold_time = self.regs.IC_SDA_HOLD.extract();
-            self.sda_hold_time = Some(sda_hold_time);
+            let sda_hold_time = self.regs.IC_SDA_HOLD().extract();
+            bus.sda_hold_time = Some(sda_hold_time);
         } else {
             let ext_sda_hold_time = 
                 math::div_round_closest_ull(
-                    (self.ext_config.clk_rate_khz * ext_sda_hold_ns).into(),
+                    (bus.clk_rate_khz * ext_sda_hold_ns).into(),
                     math::MICRO);
             // Workaround for avoiding TX arbitration lost in case I2C
             // slave pulls SDA down "too quickly" after falling edge of
@@ -244,49 +439,128 @@ impl I2cDwCoreDriver {
             if !sda_hold_time.is_set(IC_SDA_HOLD::SDA_RX_HOLD) {
                 sda_hold_time.modify(IC_SDA_HOLD::SDA_RX_HOLD.val(1));
             }
-            self.sda_hold_time = Some(sda_hold_time);
+            bus.sda_hold_time = Some(sda_hold_time);
         }
         log_info!(
             "sda hold time Tx:Rx = {}:{}",
-            self.sda_hold_time.as_ref().unwrap().read(IC_SDA_HOLD::SDA_TX_HOLD),
-            self.sda_hold_time.as_ref().unwrap().read(IC_SDA_HOLD::SDA_RX_HOLD)
+            bus.sda_hold_time.as_ref().unwrap().read(IC_SDA_HOLD::SDA_TX_HOLD),
+            bus.sda_hold_time.as_ref().unwrap().read(IC_SDA_HOLD::SDA_RX_HOLD)
         );
 
-        log_info!("I2C  Bus Speed: {}", self.speed_mode);
+        log_info!("I2C  Bus Speed: {}", bus.speed_mode);
         Ok(())
     }
 
-    pub(crate) fn write_lhcnt(&self, lhcnt: &DwI2cSclLHCnt) {
+    pub(crate) fn smbus_cnt_init(&self, bus: &mut DwI2cBusTiming) -> Result<()> {
+        let timeouts = match self.ext_config.smbus_timeouts {
+            Some(timeouts) => timeouts,
+            None => {
+                bus.smbus_cnt = None;
+                return Ok(());
+            }
+        };
+
+        // clk_rate_khz is the number of ic_clk cycles per ms
+        let ic_clk: u64 = bus.clk_rate_khz.into();
+        let to_cnt = |ms: u32| -> Result<u32> {
+            match (ic_clk * ms as u64).try_into() {
+                Ok(cnt) if ms != 0 => Ok(cnt),
+                _ => {
+                    log_err!("SMBus timeout {} ms out of range", ms);
+                    to_error(Errno::InvalidArgs)
+                }
+            }
+        };
+        let clk_low_sext = to_cnt(timeouts.clk_low_sext_ms)?;
+        let clk_low_mext = to_cnt(timeouts.clk_low_mext_ms)?;
+
+        // tHIGH:MAX = 50 us
+        let thigh_max_idle = match math::div_round_closest_ull(ic_clk * 50, 1000).try_into() {
+            Ok(cnt) => cnt,
+            Err(_) => {
+                log_err!("ic_clk too fast for SMBus tHIGH:MAX");
+                return to_error(Errno::InvalidArgs);
+            }
+        };
+
+        bus.smbus_cnt = Some(DwI2cSmbusCnt {
+            clk_low_sext,
+            clk_low_mext,
+            thigh_max_idle,
+        });
+        log_info!(
+            "SMBus CLOCK_LOW_SEXT:MEXT = {}:{}, THIGH_MAX_IDLE = {}",
+            clk_low_sext,
+            clk_low_mext,
+            thigh_max_idle
+        );
+        Ok(())
+    }
+
+    pub(crate) fn write_smbus_cnt(&self, bus: &DwI2cBusTiming) {
+        if let Some(cnt) = bus.smbus_cnt.as_ref() {
+            self.regs.IC_SMBUS_CLOCK_LOW_SEXT().set(cnt.clk_low_sext);
+            self.regs.IC_SMBUS_CLOCK_LOW_MEXT().set(cnt.clk_low_mext);
+            self.regs
+                .IC_SMBUS_THIGH_MAX_IDLE_COUNT()
+                .set(cnt.thigh_max_idle.into());
+        }
+    }
+
+    /// Reset the SMBus devices by holding SCL low for longer than
+    /// tTIMEOUT:MAX (35 ms)
+    pub(crate) fn smbus_clk_reset(&self) -> Result<()> {
+        self.regs.IC_ENABLE().modify(IC_ENABLE::SMBUS_CLK_RESET.val(1));
+        if let Err(e) = read_poll_timeout(
+            || self.regs.IC_ENABLE().extract(),
+            move |x| !x.is_set(IC_ENABLE::SMBUS_CLK_RESET),
+            1000,
+            100000,
+            false,
+        ) {
+            log_err!("{:?} while resetting SMBus clock", e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_lhcnt(&self, lhcnt: &DwI2cSclLHCnt, speed_mode: I2cSpeedMode) {
         // Write standard speed timing parameters
-        self.regs.IC_SS_OR_UFM_SCL_LCNT.set(lhcnt.ss_lcnt.into());
-        self.regs.IC_SS_OR_UFM_SCL_HCNT.set(lhcnt.ss_hcnt.into());
+        self.regs.IC_SS_OR_UFM_SCL_LCNT().set(lhcnt.ss_lcnt.into());
+        self.regs.IC_SS_OR_UFM_SCL_HCNT().set(lhcnt.ss_hcnt.into());
 
         log_info!("write SCL_LCNT:HCNT  {}:{}", lhcnt.ss_lcnt, lhcnt.ss_hcnt);
         // Write fast mode/fast mode plus timing parameters
-        self.regs.IC_FS_SCL_LCNT.set(lhcnt.fs_lcnt.into());
+        self.regs.IC_FS_SCL_LCNT().set(lhcnt.fs_lcnt.into());
         self.regs
-            .IC_FS_SCL_HCNT_OR_UFM_TBUF_CNT
+            .IC_FS_SCL_HCNT_OR_UFM_TBUF_CNT()
             .set(lhcnt.fs_hcnt.into());
 
         log_info!("write FS_SCL_LCNT:HCNT  {}:{}", lhcnt.fs_lcnt, lhcnt.fs_hcnt);
         // Write high speed timing parameters if supported
-        if self.speed_mode == I2cSpeedMode::HighSpeedMode {
-            self.regs.IC_HS_SCL_LCNT.set(lhcnt.hs_lcnt.into());
-            self.regs.IC_HS_SCL_HCNT.set(lhcnt.hs_hcnt.into());
+        if speed_mode == I2cSpeedMode::HighSpeedMode {
+            self.regs.IC_HS_SCL_LCNT().set(lhcnt.hs_lcnt.into());
+            self.regs.IC_HS_SCL_HCNT().set(lhcnt.hs_hcnt.into());
             log_info!("write HS_SCL_LCNT:HCNT {}:{}", lhcnt.hs_lcnt, lhcnt.hs_hcnt);
         }
     }
 
     #[inline]
     pub(crate) fn write_fifo(&self, ic_tx: u32, ic_rx: u32) {
-        self.regs.IC_TX_TL.set(ic_tx);
-        self.regs.IC_RX_TL.set(ic_rx);
+        self.regs.IC_TX_TL().set(ic_tx);
+        self.regs.IC_RX_TL().set(ic_rx);
         log_info!("write fifo tx:rx {}:{}", ic_tx, ic_rx);
     }
 
+    /// IC_RX_TL can be written with the adapter enabled
+    #[inline]
+    pub(crate) fn write_rx_tl(&self, ic_rx: u32) {
+        self.regs.IC_RX_TL().set(ic_rx);
+    }
+
     pub(crate) fn wait_bus_not_busy(&self) -> Result<()> {
         if let Err(e) = read_poll_timeout(
-            || return self.regs.IC_STATUS.extract(),
+            || return self.regs.IC_STATUS().extract(),
             move |x| !x.is_set(IC_STATUS::ACTIVITY),
             1100,
             20000,
@@ -301,17 +575,22 @@ impl I2cDwCoreDriver {
     }
 
     pub(crate) fn ic_enable(&self) -> LocalRegisterCopy<u32, IC_ENABLE::Register> {
-        self.regs.IC_ENABLE.extract()
+        self.regs.IC_ENABLE().extract()
     }
 
     #[inline]
     pub(crate) fn ic_enable_status(&self) -> LocalRegisterCopy<u32, IC_ENABLE_STATUS::Register> {
-        self.regs.IC_ENABLE_STATUS.extract()
+        self.regs.IC_ENABLE_STATUS().extract()
+    }
+
+    #[inline]
+    pub(crate) fn ic_intr_stat(&self) -> LocalRegisterCopy<u32, IC_INTR::Register> {
+        self.regs.IC_INTR_STAT().extract()
     }
 
     #[inline]
     pub(crate) fn ic_raw_intr_stat(&self) -> LocalRegisterCopy<u32, IC_INTR::Register> {
-        self.regs.IC_RAW_INTR_STAT.extract()
+        self.regs.IC_RAW_INTR_STAT().extract()
     }
 
     pub(crate) fn read_and_clean_intrbits(
@@ -328,7 +607,7 @@ impl I2cDwCoreDriver {
         // equals to,
         // stat = readl(IC_RAW_INTR_STAT) & readl(IC_INTR_MASK);
         // The raw version might be useful for debugging purposes.
-        let stat = self.regs.IC_INTR_STAT.extract();
+        let stat = self.regs.IC_INTR_STAT().extract();
         
         let mut abort_source = LocalRegisterCopy::new(0);
         // Do not use the IC_CLR_INTR register to clear interrupts, or
@@ -337,91 +616,182 @@ impl I2cDwCoreDriver {
         // Instead, use the separately-prepared IC_CLR_* registers.
 
         if stat.is_set(IC_INTR::RX_UNDER) {
-            let _ = self.regs.IC_CLR_RX_UNDER.get();
+            let _ = self.regs.IC_CLR_RX_UNDER().get();
         }
         if stat.is_set(IC_INTR::RX_OVER) {
-            let _ = self.regs.IC_CLR_RX_OVER.get();
+            let _ = self.regs.IC_CLR_RX_OVER().get();
         }
         if stat.is_set(IC_INTR::TX_OVER) {
-            let _ = self.regs.IC_CLR_TX_OVER.get();
+            let _ = self.regs.IC_CLR_TX_OVER().get();
         }
         if stat.is_set(IC_INTR::RD_REQ) {
-            let _ = self.regs.IC_CLR_RD_REQ.get();
+            let _ = self.regs.IC_CLR_RD_REQ().get();
         }
         if stat.is_set(IC_INTR::TX_ABRT) {
             // The IC_TX_ABRT_SOURCE register is cleared whenever
             // the IC_CLR_TX_ABRT is read.  Preserve it beforehand.
-            abort_source = self.regs.IC_TX_ABRT_SOURCE.extract();
-            let _ = self.regs.IC_CLR_TX_ABRT.get();
+            abort_source = self.regs.IC_TX_ABRT_SOURCE().extract();
+            let _ = self.regs.IC_CLR_TX_ABRT().get();
         }
         if stat.is_set(IC_INTR::RX_DONE) {
-            let _ = self.regs.IC_CLR_RX_DONE.get();
+            let _ = self.regs.IC_CLR_RX_DONE().get();
         }
         if stat.is_set(IC_INTR::ACTIVITY) {
-            let _ = self.regs.IC_CLR_ACTIVITY.get();
+            let _ = self.regs.IC_CLR_ACTIVITY().get();
         }
         if stat.is_set(IC_INTR::STOP_DET) {
             if rx_outstanding == 0 || stat.is_set(IC_INTR::RX_FULL) {
-                let _ = self.regs.IC_CLR_STOP_DET.get();
+                let _ = self.regs.IC_CLR_STOP_DET().get();
             }
         }
         if stat.is_set(IC_INTR::START_DET) {
-            let _ = self.regs.IC_CLR_START_DET.get();
+            let _ = self.regs.IC_CLR_START_DET().get();
         }
         if stat.is_set(IC_INTR::GEN_CALL) {
-            let _ = self.regs.IC_CLR_GEN_CALL.get();
+            let _ = self.regs.IC_CLR_GEN_CALL().get();
         }
         (stat, abort_source)
     }
 
+    #[inline]
+    pub(crate) fn ic_status(&self) -> LocalRegisterCopy<u32, IC_STATUS::Register> {
+        self.regs.IC_STATUS().extract()
+    }
+
+    #[inline]
+    pub(crate) fn write_smbus_interrupt_mask(
+        &self,
+        mask: &LocalRegisterCopy<u32, IC_SMBUS_INTR::Register>,
+    ) {
+        self.regs.IC_SMBUS_INTR_MASK().set(mask.get());
+    }
+
+    pub(crate) fn read_and_clean_smbus_intrbits(
+        &self,
+    ) -> LocalRegisterCopy<u32, IC_SMBUS_INTR::Register> {
+        // Like IC_INTR_STAT, IC_SMBUS_INTR_STAT only reports the unmasked
+        // bits. IC_CLR_SMBUS_INTR is write-one-to-clear, so only acknowledge
+        // the bits we are about to handle.
+        let stat = self.regs.IC_SMBUS_INTR_STAT().extract();
+        if stat.get() != 0 {
+            self.regs.IC_CLR_SMBUS_INTR().set(stat.get());
+        }
+        stat
+    }
+
+    /// Read `buf.len()` bytes from the current target by polling.
+    ///
+    /// Used for the short, fixed-size protocol reads (e.g. the SMBus Alert
+    /// Response Address) that run outside the interrupt driven transfer.
+    /// The caller must have programmed IC_TAR, masked all interrupts and
+    /// enabled the controller, and `buf` must fit in the TX FIFO.
+    pub(crate) fn polled_read(&self, buf: &mut [u8]) -> Result<()> {
+        let len = buf.len();
+        for i in 0..len {
+            let mut cmd: LocalRegisterCopy<u32, IC_DATA_CMD::Register> = LocalRegisterCopy::new(0);
+            cmd.modify(IC_DATA_CMD::CMD.val(0b1));
+            if i == len - 1 {
+                cmd.modify(IC_DATA_CMD::STOP.val(0b1));
+            }
+            self.write_ic_data_cmd(&cmd);
+        }
+
+        for byte in buf.iter_mut() {
+            read_poll_timeout(
+                || (self.ic_raw_intr_stat(), self.ic_rxflr().get()),
+                move |(stat, rxflr)| stat.is_set(IC_INTR::TX_ABRT) || rxflr > 0,
+                10,
+                20000,
+                false,
+            )?;
+
+            if self.ic_raw_intr_stat().is_set(IC_INTR::TX_ABRT) {
+                let abort_source = self.regs.IC_TX_ABRT_SOURCE().get();
+                let _ = self.regs.IC_CLR_TX_ABRT().get();
+                log_err!("polled read aborted, abort source: {:x}", abort_source);
+                return to_error(Errno::Io);
+            }
+            *byte = self.ic_data_cmd().read(IC_DATA_CMD::DAT) as u8;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub(crate) fn write_ic_tar(&self, tar: &LocalRegisterCopy<u32, IC_TAR::Register>) {
-        self.regs.IC_TAR.set(tar.get());
+        self.regs.IC_TAR().set(tar.get());
+    }
+
+    #[inline]
+    pub(crate) fn write_ic_sar(&self, addr: u16) {
+        self.regs.IC_SAR().write(IC_SAR::SAR.val(addr.into()));
+    }
+
+    #[inline]
+    pub(crate) fn enable_ack_general_call(&self, enable: bool) {
+        if enable {
+            self.regs
+                .IC_ACK_GENERAL_CALL()
+                .write(IC_ACK_GENERAL_CALL::ACK_GENERAL_CALL.val(0b1));
+        } else {
+            self.regs
+                .IC_ACK_GENERAL_CALL()
+                .write(IC_ACK_GENERAL_CALL::ACK_GENERAL_CALL.val(0b0));
+        }
+    }
+
+    #[inline]
+    pub(crate) fn write_optional_sar(&self, addr: u16) {
+        self.regs.IC_OPTIONAL_SAR().write(IC_OPTION_SAR::OPTION_SAR.val(addr.into()));
     }
 
     #[inline]
     pub(crate) fn ic_data_cmd(&self) -> LocalRegisterCopy<u32, IC_DATA_CMD::Register> {
-        self.regs.IC_DATA_CMD.extract()
+        self.regs.IC_DATA_CMD().extract()
     }
 
     #[inline]
     pub(crate) fn write_ic_data_cmd(&self,
         cmd: &LocalRegisterCopy<u32, IC_DATA_CMD::Register>){
-        self.regs.IC_DATA_CMD.set(cmd.get())
+        self.regs.IC_DATA_CMD().set(cmd.get())
     }
 
     #[inline]
     pub(crate) fn ic_txflr(&self) -> LocalRegisterCopy<u32, IC_GENERAL_FLR::Register> {
-        self.regs.IC_TXFLR.extract()
+        self.regs.IC_TXFLR().extract()
     }
 
     #[inline]
     pub(crate) fn ic_rxflr(&self) -> LocalRegisterCopy<u32, IC_GENERAL_FLR::Register> {
-        self.regs.IC_RXFLR.extract()
+        self.regs.IC_RXFLR().extract()
+    }
+
+    #[inline]
+    pub(crate) fn interrupt_mask(&self) -> LocalRegisterCopy<u32, IC_INTR::Register> {
+        self.regs.IC_INTR_MASK().extract()
     }
 
     #[inline]
     pub(crate) fn write_interrupt_mask(&self, mask: &LocalRegisterCopy<u32, IC_INTR::Register>) {
-        self.regs.IC_INTR_MASK.set(mask.get());
+        self.regs.IC_INTR_MASK().set(mask.get());
     }
 
     #[inline]
     pub(crate) fn enable_tx_empty_intr(&self, enable: bool) {
         if enable {
-            self.regs.IC_INTR_MASK.modify(IC_INTR::TX_EMPTY.val(0b1));
+            self.regs.IC_INTR_MASK().modify(IC_INTR::TX_EMPTY.val(0b1));
         } else {
-            self.regs.IC_INTR_MASK.modify(IC_INTR::TX_EMPTY.val(0b0));
+            self.regs.IC_INTR_MASK().modify(IC_INTR::TX_EMPTY.val(0b0));
         }
     }
 
     #[inline]
     pub(crate) fn disable_all_interrupt(&self) {
-        self.regs.IC_INTR_MASK.set(0);
+        self.regs.IC_INTR_MASK().set(0);
     }
 
     #[inline]
     pub(crate) fn clear_all_interrupt(&self) {
-        self.regs.IC_CLR_INTR.get();
+        self.regs.IC_CLR_INTR().get();
     }
 
     #[allow(dead_code)]
@@ -433,27 +803,17 @@ impl I2cDwCoreDriver {
     }
 
     pub(crate) fn enable_controler(&self) {
-        self.regs.IC_ENABLE.set(1);
+        let mut ic_enable = self.ic_enable_smbus_bits();
+        ic_enable.modify(IC_ENABLE::ENABLE.val(1));
+        self.regs.IC_ENABLE().set(ic_enable.get());
     }
 
     pub(crate) fn disable_controler(&self) {
-        let raw_int_stat = self.regs.IC_RAW_INTR_STAT.extract();
-        let mut ic_enable = self.ic_enable();
+        let raw_int_stat = self.regs.IC_RAW_INTR_STAT().extract();
 
         let need_aborted = raw_int_stat.is_set(IC_INTR::MST_ON_HOLD);
         if need_aborted {
-            ic_enable.modify(IC_ENABLE::ABORT.val(1));
-            self.regs.IC_ENABLE.set(ic_enable.get());
-
-            if let Err(e) = read_poll_timeout(
-                || self.regs.IC_ENABLE.extract(),
-                move |x| !x.is_set(IC_ENABLE::ABORT),
-                10,
-                100,
-                false,
-            ) {
-                log_err!("{:?} while trying to abort current transfer", e);
-            }
+            let _ = self.abort(100);
         }
 
         let mut try_cnt = 100;
@@ -472,8 +832,45 @@ impl I2cDwCoreDriver {
         }
     }
 
+    /// Abort the transfer in flight: the controller issues a STOP,
+    /// flushes the TX FIFO and raises TX_ABRT with ABRT_USER_ABRT
+    pub(crate) fn abort(&self, timeout_us: u64) -> Result<()> {
+        self.regs.IC_ENABLE().modify(IC_ENABLE::ABORT.val(1));
+
+        if let Err(e) = read_poll_timeout(
+            || self.regs.IC_ENABLE().extract(),
+            move |x| !x.is_set(IC_ENABLE::ABORT),
+            10,
+            timeout_us,
+            false,
+        ) {
+            log_err!("{:?} while trying to abort current transfer", e);
+            return Err(e);
+        }
+        Ok(())
+    }
+
     fn disable_nowait(&self) {
-        self.regs.IC_ENABLE.set(0);
+        self.regs.IC_ENABLE().set(self.ic_enable_smbus_bits().get());
+    }
+
+    /// SMBus signal bits of IC_ENABLE, they drive SMBSUS#/SMBALERT# and
+    /// must survive enabling and disabling the adapter
+    fn ic_enable_smbus_bits(&self) -> LocalRegisterCopy<u32, IC_ENABLE::Register> {
+        let ic_enable = self.ic_enable();
+        let mut bits = LocalRegisterCopy::new(0);
+        bits.modify(IC_ENABLE::SMBUS_SUSPEND_EN.val(ic_enable.read(IC_ENABLE::SMBUS_SUSPEND_EN)));
+        bits.modify(IC_ENABLE::SMBUS_ALERT_EN.val(ic_enable.read(IC_ENABLE::SMBUS_ALERT_EN)));
+        bits
+    }
+
+    #[inline]
+    pub(crate) fn enable_smbus_suspend(&self, enable: bool) {
+        if enable {
+            self.regs.IC_ENABLE().modify(IC_ENABLE::SMBUS_SUSPEND_EN.val(0b1));
+        } else {
+            self.regs.IC_ENABLE().modify(IC_ENABLE::SMBUS_SUSPEND_EN.val(0b0));
+        }
     }
 }
 
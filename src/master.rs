@@ -1,12 +1,18 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
 use osl::{
     error::{to_error, Errno, Result},
+    sleep::usleep,
     vec::Vec,
-    sync::{OslCompletion,GeneralComplete, SpinLock, new_spinlock, Arc},
+    sync::{SpinLock, new_spinlock},
     driver::irq,
     driver::irq::{to_irq_return, ReturnEnum},
-    driver::i2c::{I2cMsg, I2cMsgFlags, I2cFuncFlags, I2cSpeedMode, I2C_SMBUS_BLOCK_MAX, GeneralI2cMsg},
+    driver::i2c::{I2cMsg, I2cMsgFlags, I2cFuncFlags, I2cSpeedMode, I2cTiming, GeneralI2cMsg},
 };
 
+#[cfg(feature = "linux")]
+use osl::sync::Arc;
+
 #[allow(unused_imports)]
 use tock_registers::{
     LocalRegisterCopy,
@@ -15,9 +21,18 @@ use tock_registers::{
 
 #[allow(unused_imports)]
 use crate::{
-    common::{DwI2cCmdErr, DwI2cSclLHCnt, DwI2cStatus},
+    common::{DwI2cBusLock, DwI2cCmdErr, DwI2cCompletion, DwI2cSclLHCnt, DwI2cStatus},
     registers::*,
-    I2cDwCoreDriver, I2cDwDriverConfig,
+    smbus::{
+        SmbusAlertHandler, SmbusAlertHandlers, SmbusHostNotifyHandler, SMBUS_ALERT_MAX_LOOPS,
+        SMBUS_ARA_ADDR, SMBUS_HOST_NOTIFY_LEN,
+    },
+    operation::{Operation, XferMsgs},
+    pm::I2cDwPlatformHooks,
+    quirks::{I2cDwAdapterQuirks, DW_I2C_QUIRKS, I2C_DW_XFER_MAX_MSGS},
+    semaphore::{I2cDwBusSemaphore, DW_I2C_SEMAPHORE_TIMEOUT_US},
+    DwI2cBusTiming, DwI2cHwParams, I2cDwCoreDriver, I2cDwDriverConfig, I2cDwMmio,
+    I2cDwRegAccess, I2cDwRxThreshold,
 };
 
 enum TransferResult  {
@@ -31,11 +46,57 @@ enum TransferResult  {
     Continue,
 }
 
+/// I2C Device ID, read through the Device ID address 0x7C
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct I2cDeviceId {
+    /// 12-bit manufacturer, assigned by NXP
+    pub manufacturer: u16,
+    /// 9-bit part identification, assigned by the manufacturer
+    pub part_id: u16,
+    /// 3-bit die revision
+    pub revision: u8,
+}
+
+impl I2cDeviceId {
+    /// Decode the 24-bit Device ID, as sent MSB first on the bus
+    pub fn from_raw(raw: u32) -> Self {
+        Self {
+            manufacturer: ((raw >> 12) & 0xfff) as u16,
+            part_id: ((raw >> 3) & 0x1ff) as u16,
+            revision: (raw & 0x7) as u8,
+        }
+    }
+}
+
+/// Time for the controller to finish the current byte and STOP on a
+/// user abort, one byte at 100kHz is 90us
+const ABORT_TIMEOUT_US: u64 = 1000;
+
+/// Retry policy for transfers which lost arbitration on a multi-master bus
+#[derive(Debug, Clone, Copy, Default)]
+pub struct I2cDwRetryPolicy {
+    /// Max number of retries after the first attempt, 0 disables retrying
+    pub retries: u32,
+    /// Time to back off before retrying
+    pub backoff_us: u64,
+}
+
+/// How IC_TAR addresses the bus
+#[derive(Clone, Copy)]
+enum TarMode {
+    // Address the target of the msgs
+    Normal,
+    // General Call address 0x00, write only
+    GeneralCall,
+    // START byte before addressing the target of the msgs
+    StartByte,
+}
+
 /// Master driver transfer abstract
 #[allow(dead_code)]
 struct MasterXfer {
     /// XferData
-    msgs: Vec<I2cMsg>,
+    msgs: XferMsgs,
     /// run time hadware error code
     cmd_err: DwI2cCmdErr,
     /// the element index of the current rx message in the msgs array
@@ -48,29 +109,38 @@ struct MasterXfer {
     abort_source: LocalRegisterCopy<u32, IC_TX_ABRT_SOURCE::Register>,
     /// current master-rx elements in tx fifo
     rx_outstanding: isize,
+    /// IC_RX_TL as last written
+    rx_tl: u32,
     /// Driver Status
     status: DwI2cStatus,
+    /// commands (write bytes or read requests) queued per msg
+    tx_cnt: [usize; I2C_DW_XFER_MAX_MSGS],
+    /// bytes transferred per msg
+    xfer_len: [usize; I2C_DW_XFER_MAX_MSGS],
 }
 
 impl Default for MasterXfer {
     /// Create an empty XferData
     fn default() -> Self {
         Self {
-            msgs: Vec::new(),
+            msgs: XferMsgs::default(),
             cmd_err: DwI2cCmdErr::from_bits(0).unwrap(),
             msg_read_idx: 0,
             msg_write_idx: 0,
             msg_err: Ok(()),
             abort_source: LocalRegisterCopy::new(0),
             rx_outstanding: 0,
+            rx_tl: 0,
             status: DwI2cStatus::empty(),
+            tx_cnt: [0; I2C_DW_XFER_MAX_MSGS],
+            xfer_len: [0; I2C_DW_XFER_MAX_MSGS],
         }
     }
 }
 
 impl MasterXfer {
     #[allow(dead_code)]
-    fn init(&mut self, msgs: Vec<I2cMsg>) {
+    fn init(&mut self, msgs: XferMsgs) {
         self.msgs = msgs;
         self.cmd_err = DwI2cCmdErr::from_bits(0).unwrap();
         self.msg_read_idx = 0;
@@ -78,7 +148,98 @@ impl MasterXfer {
         self.msg_err = Ok(());
         self.abort_source = LocalRegisterCopy::new(0);
         self.rx_outstanding = 0;
+        self.rx_tl = 0;
         self.status = DwI2cStatus::empty();
+        self.tx_cnt = [0; I2C_DW_XFER_MAX_MSGS];
+        self.xfer_len = [0; I2C_DW_XFER_MAX_MSGS];
+    }
+
+    /// Rewind the msgs for another attempt, the counters then tell the
+    /// progress of that attempt only, false if they were not staged
+    fn restart(&mut self) -> bool {
+        if !self.msgs.rewind() {
+            return false;
+        }
+        let msgs = core::mem::take(&mut self.msgs);
+        self.init(msgs);
+        true
+    }
+
+    /// Check the msgs against the adapter quirks, `Errno::NotSupported`
+    /// for what the adapter can not do, `Errno::InvalidArgs` for msgs
+    /// no adapter can transfer
+    fn check_quirks(&mut self, quirks: &I2cDwAdapterQuirks) -> Result<()> {
+        let msg_len = self.msgs.len();
+        if msg_len == 0 {
+            log_err!("no msgs to transfer");
+            return to_error(Errno::InvalidArgs);
+        }
+
+        if msg_len > quirks.max_num_msgs {
+            log_err!("{} msgs, at most {} per transfer", msg_len, quirks.max_num_msgs);
+            return to_error(Errno::NotSupported);
+        }
+
+        let addr = self.msgs.get_mut(0).addr();
+        let mut recv_len_read = false;
+        for i in 0..msg_len {
+            let msg = self.msgs.get_mut(i);
+            let flags = msg.flags();
+            let read = flags.contains(I2cMsgFlags::I2cMasterRead);
+
+            if quirks.single_addr && msg.addr() != addr &&
+                !flags.contains(I2cMsgFlags::I2cMasterNoStart)
+            {
+                log_err!("msg {}: address 0x{:x} differs from 0x{:x}", i, msg.addr(), addr);
+                return to_error(Errno::NotSupported);
+            }
+
+            // nothing queued yet, so all sent means nothing to send
+            if quirks.no_zero_len && msg.send_end() {
+                log_err!("msg {}: zero-length msg", i);
+                return to_error(Errno::NotSupported);
+            }
+
+            if flags.contains(I2cMsgFlags::I2cMasterRecvLen) && !read {
+                log_err!("msg {}: RECV_LEN on a write", i);
+                return to_error(Errno::InvalidArgs);
+            }
+
+            if quirks.no_write_after_recv_len && recv_len_read && !read {
+                log_err!("msg {}: write after a RECV_LEN read", i);
+                return to_error(Errno::NotSupported);
+            }
+            recv_len_read |= flags.contains(I2cMsgFlags::I2cMasterRecvLen);
+
+            let unsupported = flags & quirks.unsupported_flags;
+            if !unsupported.is_empty() {
+                log_err!("msg {}: unsupported flags {:?}", i, unsupported);
+                return to_error(Errno::NotSupported);
+            }
+
+            if quirks.nostart_write_only && flags.contains(I2cMsgFlags::I2cMasterNoStart) {
+                let prev_flags = match i {
+                    0 => None,
+                    _ => Some(self.msgs.get_mut(i - 1).flags()),
+                };
+                let continues_write = prev_flags.is_some_and(|prev| {
+                    !prev.contains(I2cMsgFlags::I2cMasterRead) &&
+                        !prev.contains(I2cMsgFlags::I2cMasterStop)
+                });
+                if read || !continues_write {
+                    log_err!("msg {}: NOSTART only continues a previous write", i);
+                    return to_error(Errno::NotSupported);
+                }
+            }
+
+            if quirks.ignore_nak_last_only && flags.contains(I2cMsgFlags::I2cMasterIgnoreNak) &&
+                (read || i != msg_len - 1)
+            {
+                log_err!("msg {}: IGNORE_NAK only on a write as last msg", i);
+                return to_error(Errno::NotSupported);
+            }
+        }
+        Ok(())
     }
 
     #[inline]
@@ -115,13 +276,12 @@ impl MasterXfer {
         }
     }
 
-    fn prepare(&mut self, msgs: Vec<I2cMsg>, master_driver: &I2cDwMasterDriver) {
-        self.init(msgs);
+    fn prepare<A: I2cDwRegAccess>(&mut self, tar_mode: TarMode, master_driver: &I2cDwMasterDriver<A>) {
         let core_driver = &master_driver.driver;
         // disable the adapter
         master_driver.disable(false);
 
-        let first_msg = &self.msgs[self.msg_write_idx as usize];
+        let first_msg = self.msgs.get_mut(self.msg_write_idx);
         let mut ic_tar: LocalRegisterCopy<u32, IC_TAR::Register> = LocalRegisterCopy::new(0);
         if first_msg.flags().contains(I2cMsgFlags::I2cAddrTen){
             core_driver.enable_10bitaddr(true);
@@ -131,7 +291,20 @@ impl MasterXfer {
         }
 
         ic_tar.modify(IC_TAR::TAR.val(first_msg.addr().into()));
+        match tar_mode {
+            TarMode::Normal => (),
+            TarMode::GeneralCall => {
+                ic_tar.modify(IC_TAR::SPECIAL.val(0b1));
+                ic_tar.modify(IC_TAR::GC_OR_START.val(0b0));
+            }
+            TarMode::StartByte => {
+                ic_tar.modify(IC_TAR::SPECIAL.val(0b1));
+                ic_tar.modify(IC_TAR::GC_OR_START.val(0b1));
+            }
+        }
         core_driver.write_ic_tar(&ic_tar);
+        // no read is outstanding yet
+        core_driver.write_rx_tl(0);
 
         // Enforce disabled interrupts (due to HW issues) 
         core_driver.disable_all_interrupt();
@@ -143,7 +316,7 @@ impl MasterXfer {
         let _ = core_driver.ic_enable_status();
     }
 
-    fn irq_process(&mut self, master_driver: &I2cDwMasterDriver) -> TransferResult {
+    fn irq_process<A: I2cDwRegAccess>(&mut self, master_driver: &I2cDwMasterDriver<A>) -> TransferResult {
         let core_driver = &master_driver.driver;
         let (stat, abort_source) = 
             core_driver.read_and_clean_intrbits(self.rx_outstanding);
@@ -154,7 +327,6 @@ impl MasterXfer {
         // disable interrupts for suppressing further interrupts if
         // interrupt really came from this HW (E.g. firmware has left
         // the HW active).
-        assert!(self.is_active());
         if !self.is_active() {
             return TransferResult::UnExpectedInterrupt; 
         }
@@ -174,15 +346,24 @@ impl MasterXfer {
             self.write_msgs(&master_driver);
         }
 
-        if  (stat.is_set(IC_INTR::STOP_DET) || self.msg_err.is_err()) 
-            && self.rx_outstanding == 0 {
+        self.update_rx_tl(master_driver);
+
+        // A STOP forced by I2cMasterStop does not end the transfer, even
+        // with all msgs queued: only the STOP after which the TX FIFO is
+        // drained and the master is idle does
+        let all_sent = self.msg_write_idx >= self.msgs.len();
+        let stopped = stat.is_set(IC_INTR::STOP_DET) && all_sent && {
+            let ic_status = core_driver.ic_status();
+            ic_status.is_set(IC_STATUS::TFE) && !ic_status.is_set(IC_STATUS::MST_ACTIVITY)
+        };
+        if  (stopped || self.msg_err.is_err()) && self.rx_outstanding == 0 {
                 return TransferResult::Fininsh;
         }
 
         return TransferResult::Continue;
     }
 
-    fn exit(&mut self, master_driver: &I2cDwMasterDriver) -> Result<()> {
+    fn exit<A: I2cDwRegAccess>(&mut self, master_driver: &I2cDwMasterDriver<A>) -> Result<()> {
         // We must disable the adapter before returning and signaling the end
         // of the current transfer. Otherwise the hardware might continue
         // generating interrupts which in turn causes a race condition with
@@ -191,6 +372,12 @@ impl MasterXfer {
         // handle them correctly yet.
         master_driver.disable(true);
         self.clear_active();
+        let flushed = if self.cmd_err.contains(DwI2cCmdErr::TX_ABRT) {
+            self.abort_source.read(IC_TX_ABRT_SOURCE::TX_FLUSH_CNT) as usize
+        } else {
+            0
+        };
+        self.account_xfer_len(flushed);
 
         match self.msg_err {
             Err(e) => {
@@ -200,6 +387,21 @@ impl MasterXfer {
             Ok(_) => {},
         }
 
+        if self.cmd_err.contains(DwI2cCmdErr::USER_ABRT) {
+            log_err!("i2c dw transfer aborted by user");
+            return to_error(Errno::Canceled);
+        }
+
+        if self.cmd_err.contains(DwI2cCmdErr::CLK_EXTND_TIMEOUT) {
+            log_err!("i2c dw transfer SMBus clock extension timeout");
+            return to_error(Errno::Protocol);
+        }
+
+        if self.cmd_err == DwI2cCmdErr::TX_ABRT && self.nak_ignored() {
+            log_debug!("i2c dw transfer ignored NAK of the last byte");
+            return Ok(());
+        }
+
         match self.cmd_err {
             DwI2cCmdErr::TX_ABRT => {
                 log_err!("i2c dw transfer recv tx_abort");
@@ -216,26 +418,68 @@ impl MasterXfer {
     }
 
 
+    /// Work out the bytes each write msg put on the bus: everything it
+    /// queued, less its share of the `flushed` TX FIFO entries, which are
+    /// the last queued ones. Read msgs count received bytes.
+    fn account_xfer_len(&mut self, mut flushed: usize) {
+        // check_quirks caps the msgs to the counters
+        for i in (0..self.msgs.len()).rev() {
+            let queued = self.tx_cnt[i];
+            if self.msgs.get_mut(i).flags().contains(I2cMsgFlags::I2cMasterRead) {
+                // read requests which got no data yet
+                flushed -= flushed.min(queued.saturating_sub(self.xfer_len[i]));
+            } else {
+                let flushed_here = flushed.min(queued);
+                flushed -= flushed_here;
+                self.xfer_len[i] = queued - flushed_here;
+            }
+        }
+    }
+
+    /// The last msg has I2cMasterIgnoreNak and only its last byte was
+    /// NAKed, so all of it was sent
+    fn nak_ignored(&mut self) -> bool {
+        let msg_len = self.msgs.len();
+        let mut others = self.abort_source;
+        others.modify(IC_TX_ABRT_SOURCE::ABRT_TXDATA_NOACK.val(0) + IC_TX_ABRT_SOURCE::TX_FLUSH_CNT.val(0));
+
+        self.abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_TXDATA_NOACK) &&
+            others.get() == 0 &&
+            self.abort_source.read(IC_TX_ABRT_SOURCE::TX_FLUSH_CNT) == 0 &&
+            self.msg_write_idx == msg_len &&
+            self.msgs.get_mut(msg_len - 1).flags().contains(I2cMsgFlags::I2cMasterIgnoreNak)
+    }
+
     fn handle_tx_abort(&mut self) -> Result<()> {
         let abort_source = self.abort_source;
+        if abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_USER_ABRT){
+            log_err!("i2c dw transfer aborted by user");
+            return to_error(Errno::Canceled);
+        }
         if abort_source.matches_any(&self.tx_abort_noack()){
             return to_error(Errno::Io);
         }
-        if abort_source.is_set(IC_TX_ABRT_SOURCE::ARB_LOST){
+        if Self::arbitration_lost(abort_source) {
             return to_error(Errno::Again);
         } else if abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_GCALL_READ){
             return to_error(Errno::InvalidArgs);
+        } else if abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_SBYTE_NORSTRT){
+            // START byte needs IC_RESTART_EN
+            return to_error(Errno::InvalidArgs);
+        } else if abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_SBYTE_ACKDET){
+            log_err!("START byte acknowledged by a target");
+            return to_error(Errno::Io);
         } else {
             return to_error(Errno::Io);
         }
     }
 
-    fn write_msgs(&mut self, master_driver: &I2cDwMasterDriver) {
+    fn write_msgs<A: I2cDwRegAccess>(&mut self, master_driver: &I2cDwMasterDriver<A>) {
         let msg_len = self.msgs.len();
         let core_driver = &master_driver.driver;
         
-        let mut intr_mask = I2cDwMasterDriver::master_default_intr_mask();
-        let addr = self.msgs[self.msg_write_idx].addr();
+        let mut intr_mask = I2cDwMasterDriver::<A>::master_default_intr_mask();
+        let addr = self.msgs.get_mut(self.msg_write_idx).addr();
         let mut need_restart = false;
         loop {
             let write_idx = self.msg_write_idx;
@@ -247,16 +491,21 @@ impl MasterXfer {
                 //If both IC_EMPTYFIFO_HOLD_MASTER_EN and
                 //IC_RESTART_EN are set, we must manually
                 //set restart bit between messages.
+                //NOSTART continues the previous write, and a START
+                //follows a STOP forced by I2cMasterStop.
                 if master_driver.cfg.is_set(IC_CON::IC_RESTART_EN) && 
-                    write_idx > 0
+                    write_idx > 0 &&
+                    !self.msgs.get_mut(write_idx).flags().contains(I2cMsgFlags::I2cMasterNoStart) &&
+                    !self.msgs.get_mut(write_idx - 1).flags().contains(I2cMsgFlags::I2cMasterStop)
                 {
                     need_restart = true;           
                 }
             }
 
-            let msg = &mut self.msgs[write_idx];
+            let mut msg = self.msgs.get_mut(write_idx);
 
-            if msg.addr() != addr {
+            // the address of a NOSTART msg is not sent
+            if msg.addr() != addr && !msg.flags().contains(I2cMsgFlags::I2cMasterNoStart) {
                 self.msg_err = to_error(Errno::InvalidArgs);
                 break;
             }
@@ -281,7 +530,9 @@ impl MasterXfer {
                 // I2C_FUNC_SMBUS_BLOCK_DATA to 1. The length will
                 // be adjusted when receiving the first byte.
                 // Thus we can't stop the transaction here.
-                if write_idx == msg_len-1 &&
+                // I2cMasterStop forces a STOP after the msg.
+                if (write_idx == msg_len-1 ||
+                    msg.flags().contains(I2cMsgFlags::I2cMasterStop)) &&
                     !msg.flags().contains(I2cMsgFlags::I2cMasterRecvLen) &&
                     msg.send_left_last() {
                     cmd.modify(IC_DATA_CMD::STOP.val(0b1));
@@ -305,6 +556,9 @@ impl MasterXfer {
                 } else {
                     cmd.modify(IC_DATA_CMD::DAT.val(msg.pop_front_byte() as u32)); 
                 }
+                if let Some(cnt) = self.tx_cnt.get_mut(write_idx) {
+                    *cnt += 1;
+                }
                 core_driver.write_ic_data_cmd(&cmd);
                 tx_limit -=1;
             }
@@ -341,7 +595,19 @@ impl MasterXfer {
         core_driver.write_interrupt_mask(&intr_mask);
     }
 
-    fn read_msgs(&mut self, master_driver: &I2cDwMasterDriver) {
+    /// Let RX_FULL fire once the outstanding read bytes, at most
+    /// `rx_tl_max + 1` of them, are in the RX FIFO. A higher IC_RX_TL
+    /// would never be reached.
+    fn update_rx_tl<A: I2cDwRegAccess>(&mut self, master_driver: &I2cDwMasterDriver<A>) {
+        let outstanding = self.rx_outstanding.max(1) as u32;
+        let rx_tl = (outstanding - 1).min(master_driver.rx_tl_max);
+        if rx_tl != self.rx_tl {
+            master_driver.driver.write_rx_tl(rx_tl);
+            self.rx_tl = rx_tl;
+        }
+    }
+
+    fn read_msgs<A: I2cDwRegAccess>(&mut self, master_driver: &I2cDwMasterDriver<A>) {
         let msg_len = self.msgs.len();
         let core_driver = &master_driver.driver;
 
@@ -351,7 +617,7 @@ impl MasterXfer {
                 break;
             }
 
-            let msg = &mut(self.msgs[read_idx]);
+            let mut msg = self.msgs.get_mut(read_idx);
 
             if !msg.flags().contains(I2cMsgFlags::I2cMasterRead) {
                 self.msg_read_idx += 1;
@@ -375,7 +641,7 @@ impl MasterXfer {
                     // I2C_FUNC_SMBUS_BLOCK_DATA case. That needs to read
                     // another byte with STOP bit set when the block data
                     // response length is invalid to complete the transaction.
-                    if ic_data == 0 || ic_data > I2C_SMBUS_BLOCK_MAX {
+                    if ic_data == 0 || ic_data > DW_I2C_QUIRKS.max_recv_len {
                         ic_data = 1;
                     }
                     let mut buf_len = ic_data as usize;
@@ -397,6 +663,9 @@ impl MasterXfer {
                 }
                 msg.push_byte(ic_data.try_into().unwrap());
                 self.rx_outstanding -= 1;
+                if let Some(len) = self.xfer_len.get_mut(read_idx) {
+                    *len += 1;
+                }
             }
             
             if !msg.recieve_end() {
@@ -408,6 +677,12 @@ impl MasterXfer {
         }
     }
 
+    #[inline]
+    fn arbitration_lost(abort_source: LocalRegisterCopy<u32, IC_TX_ABRT_SOURCE::Register>) -> bool {
+        abort_source.is_set(IC_TX_ABRT_SOURCE::ARB_LOST) ||
+            abort_source.is_set(IC_TX_ABRT_SOURCE::ABRT_SLV_ARBLOST)
+    }
+
     fn tx_abort_noack(&self) -> [FieldValue<u32,IC_TX_ABRT_SOURCE::Register>;5] {
         [
             IC_TX_ABRT_SOURCE::ABRT_7B_ADDR_NOACK.val(0b1),
@@ -420,21 +695,90 @@ impl MasterXfer {
 
 }
 
+/// Bus timing and SCL counts of the master, replaced as a whole by
+/// `reconfigure`
+#[derive(Clone)]
+struct MasterTiming {
+    bus: DwI2cBusTiming,
+    /// I2c scl_LHCNT
+    lhcnt: DwI2cSclLHCnt,
+}
+
+/// SMBus interrupt mask and the handlers it is enabled for, changed
+/// together by client drivers sharing the adapter
+struct MasterSmbus {
+    /// SMBus interrupt mask
+    intr_mask: LocalRegisterCopy<u32, IC_SMBUS_INTR::Register>,
+    /// SMBus alert handlers, indexed by device address
+    alert_handlers: SmbusAlertHandlers,
+    /// SMBus Host Notify handler
+    host_notify_handler: Option<SmbusHostNotifyHandler>,
+}
+
 /// The I2cDesignware Driver
 #[allow(dead_code)]
-pub struct I2cDwMasterDriver {
+pub struct I2cDwMasterDriver<A: I2cDwRegAccess = I2cDwMmio> {
     /// I2c Config  register set value
     cfg: LocalRegisterCopy<u32, IC_CON::Register>,
     /// core Driver
-    driver: I2cDwCoreDriver,
-    /// I2c scl_LHCNT
-    lhcnt: DwI2cSclLHCnt,
+    driver: I2cDwCoreDriver<A>,
+    /// Bus timing, only replaced under the bus lock and copied out
+    /// before programming the controller, which sleeps
+    #[cfg(feature = "linux")]
+    timing: Arc<SpinLock<MasterTiming>>,
+    #[cfg(feature = "arceos")]
+    timing: SpinLock<MasterTiming>,
     /// Fifo
     tx_fifo_depth: u32,
     rx_fifo_depth: u32,
+    /// IC_TX_TL, and the highest IC_RX_TL of a read
+    tx_tl: u32,
+    rx_tl_max: u32,
     
-    /// Arc completion 
-    cmd_complete: Arc<OslCompletion>,
+    /// Transfer completion, signalled by the irq handler
+    cmd_complete: DwI2cCompletion,
+    /// Serializes transfers, held across a whole transfer
+    /// or a `lock_bus` sequence
+    bus_lock: DwI2cBusLock,
+
+    /// SMBus interrupt mask and handlers, copied out before programming
+    /// the controller
+    #[cfg(feature = "linux")]
+    smbus: Arc<SpinLock<MasterSmbus>>,
+    #[cfg(feature = "arceos")]
+    smbus: SpinLock<MasterSmbus>,
+    /// SMBALERT# was signalled and not processed yet
+    smbus_alert_pending: AtomicBool,
+    /// SMBSUS# asserted by `smbus_suspend`, restored by `master_setup`
+    smbus_suspended: AtomicBool,
+    /// Max retries after lost arbitration, see `I2cDwRetryPolicy`
+    retries_max: AtomicU32,
+    /// Back off time before a retry
+    retry_backoff_us: AtomicU64,
+    /// Retries used by the last transfer, written under the bus lock
+    retries_used: AtomicU32,
+    /// Suspended, the registers may have lost their content
+    suspended: AtomicBool,
+    /// A transfer or a polled read owns the FIFOs
+    in_flight: AtomicBool,
+
+    /// Clock and reset provider for runtime PM
+    pm_hooks: Option<&'static dyn I2cDwPlatformHooks>,
+    /// Idle time before runtime PM turns the block off
+    autosuspend_delay_us: u64,
+    /// Clocked and out of reset, always with no `pm_hooks`
+    powered: AtomicBool,
+    /// `pm_get` references not dropped by `pm_put` yet
+    pm_usage: AtomicU32,
+    /// Time after which `runtime_idle` turns the block off
+    autosuspend_deadline: AtomicU64,
+
+    /// Semaphore of a controller shared with firmware
+    bus_semaphore: Option<&'static dyn I2cDwBusSemaphore>,
+    /// Time to wait for `bus_semaphore`
+    semaphore_timeout_us: u64,
+    /// Nesting of `acquire_bus_semaphore`, under the bus lock
+    semaphore_depth: AtomicU32,
 
     /// Since xfer will be used in interrupt handler,
     /// the data needs a concurrent mechanism to ensure safety. 
@@ -449,14 +793,84 @@ pub struct I2cDwMasterDriver {
 
 impl I2cDwMasterDriver {
     /// Create a new I2cDesignwarDriver
-    pub fn new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Self {
+    ///
+    /// ## Safety
+    ///
+    /// - `base_addr` must be aligned, non-null, and dereferencable as the
+    ///   DW APB I2C register map.
+    /// - `*base_addr` must be valid for the program duration.
+    pub unsafe fn new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Self {
+        // SAFETY: as promised by the caller
+        Self::with_access(config, unsafe { I2cDwMmio::new(base_addr) })
+    }
+
+    /// Create a new I2cDesignwarDriver, failing with `Errno::InvalidArgs`
+    /// if `base_addr` is null or not 32-bit aligned
+    ///
+    /// `setup` probes the block before writing to it.
+    ///
+    /// ## Safety
+    ///
+    /// - `base_addr`, if non-null and aligned, must be dereferencable as
+    ///   the DW APB I2C register map.
+    /// - `*base_addr` must be valid for the program duration.
+    pub unsafe fn try_new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Result<Self> {
+        // SAFETY: as promised by the caller
+        Ok(Self::with_access(config, unsafe { I2cDwMmio::try_new(base_addr) }?))
+    }
+}
+
+impl<A: I2cDwRegAccess> I2cDwMasterDriver<A> {
+    /// Create a new I2cDesignwarDriver accessing its registers through
+    /// `access`
+    ///
+    /// With the `arceos` feature nothing is allocated, here or by
+    /// `master_transfer_ops`, transfers of owned msgs allocate the
+    /// completion they sleep on. The Linux kernel needs the completion
+    /// and the transfer, timing and SMBus locks pinned, so they are
+    /// allocated once here.
+    pub fn with_access(config: I2cDwDriverConfig, access: A) -> Self {
+        let timing = MasterTiming {
+            bus: DwI2cBusTiming::new(&config.timing, config.clk_rate_khz),
+            lhcnt: DwI2cSclLHCnt::default(),
+        };
+        let smbus = MasterSmbus {
+            intr_mask: LocalRegisterCopy::new(0),
+            alert_handlers: SmbusAlertHandlers::default(),
+            host_notify_handler: None,
+        };
         Self {
             cfg: LocalRegisterCopy::new(0),
-            driver: I2cDwCoreDriver::new(config, base_addr),
-            lhcnt: DwI2cSclLHCnt::default(),
+            driver: I2cDwCoreDriver::new(config, access),
+            #[cfg(feature = "linux")]
+            timing: Arc::pin_init(new_spinlock!(timing)).unwrap(),
+            #[cfg(feature = "arceos")]
+            timing: new_spinlock!(timing),
             tx_fifo_depth: 0,
             rx_fifo_depth: 0,
-            cmd_complete: OslCompletion::new().unwrap(),
+            tx_tl: 0,
+            rx_tl_max: 0,
+            cmd_complete: DwI2cCompletion::new(),
+            bus_lock: DwI2cBusLock::new(),
+            #[cfg(feature = "linux")]
+            smbus: Arc::pin_init(new_spinlock!(smbus)).unwrap(),
+            #[cfg(feature = "arceos")]
+            smbus: new_spinlock!(smbus),
+            smbus_alert_pending: AtomicBool::new(false),
+            smbus_suspended: AtomicBool::new(false),
+            retries_max: AtomicU32::new(0),
+            retry_backoff_us: AtomicU64::new(0),
+            retries_used: AtomicU32::new(0),
+            suspended: AtomicBool::new(false),
+            in_flight: AtomicBool::new(false),
+            pm_hooks: None,
+            autosuspend_delay_us: 0,
+            powered: AtomicBool::new(true),
+            pm_usage: AtomicU32::new(0),
+            autosuspend_deadline: AtomicU64::new(0),
+            bus_semaphore: None,
+            semaphore_timeout_us: DW_I2C_SEMAPHORE_TIMEOUT_US,
+            semaphore_depth: AtomicU32::new(0),
             #[cfg(feature = "linux")]
             xfer: Arc::pin_init(new_spinlock!(MasterXfer::default())).unwrap(),
             #[cfg(feature = "arceos")]
@@ -464,20 +878,58 @@ impl I2cDwMasterDriver {
         }
     }
 
+    /// Check the registers are a DW APB I2C block, reading IC_COMP_TYPE
+    /// and IC_COMP_PARAM_1 only, and pick the register access mode
+    ///
+    /// Fails with `Errno::NoSuchDevice` otherwise. The block must be
+    /// clocked and out of reset; `setup` probes by itself.
+    pub fn probe(&mut self) -> Result<()> {
+        self.driver.probe()
+    }
+
+    /// What the block was synthesized with, None until probed
+    pub fn hw_params(&self) -> Option<DwI2cHwParams> {
+        self.driver.hw_params
+    }
+
     /// Initialize the designware I2C driver config
     pub fn setup(&mut self) -> Result<()> {
-        // com and speed check must be the first step
-        self.driver.com_type_check()?;
-        self.driver.speed_check()?;
+        // the block must be clocked and out of reset before the checks
+        if let Some(hooks) = self.pm_hooks {
+            self.powered.store(false, Ordering::Release);
+            Self::power_on(hooks)?;
+            self.powered.store(true, Ordering::Release);
+            // dropped by the pm_put below
+            self.pm_usage.store(1, Ordering::Relaxed);
+        }
+        let clk_rate_khz = match self.pm_hooks.map(|hooks| hooks.clk_rate_khz()) {
+            Some(clk_rate_khz) if clk_rate_khz != 0 => clk_rate_khz,
+            _ => self.driver.ext_config.clk_rate_khz,
+        };
+
+        // firmware sharing the controller keeps off while we program it
+        self.acquire_bus_semaphore()?;
+        let ret = self.setup_owned(clk_rate_khz);
+        self.release_bus_semaphore();
+        ret?;
+        self.pm_put();
+        Ok(())
+    }
+
+    fn setup_owned(&mut self, clk_rate_khz: u32) -> Result<()> {
+        // probe and speed check must be the first step
+        self.probe()?;
+        let bus = DwI2cBusTiming::new(&self.driver.ext_config.timing, clk_rate_khz);
+        let timing = self.timing_init(bus)?;
+        *self.timing.lock() = timing;
         // init config
         self.config_init()?;
-        self.scl_lhcnt_init()?;
-        self.driver.sda_hold_time_init()?;
-        self.fifo_size_init();
+        self.fifo_size_init()?;
 
         // Initialize the designware I2C master hardware
         self.master_setup();
         self.driver.disable_all_interrupt();
+        self.idle();
         Ok(())
     }
 
@@ -500,39 +952,590 @@ impl I2cDwMasterDriver {
             self.cfg.modify(IC_CON::BUS_CLEAR_FEATURE_CTRL.val(1));
         }
 
-        self.driver.cfg_init_speed(&mut self.cfg);
+        // SMBus compliance mode, abort on clock low extension timeouts
+        if self.driver.ext_config.smbus_timeouts.is_some() {
+            let mut smbus = self.smbus.lock();
+            smbus.intr_mask.modify(IC_SMBUS_INTR::MST_CLOCK_EXTND_TIMEOUT.val(1));
+            smbus.intr_mask.modify(IC_SMBUS_INTR::SLV_CLOCK_EXTND_TIMEOUT.val(1));
+        }
+        Ok(())
+    }
+
+    /// Change the bus timing and the IC clock rate, e.g. to change the bus
+    /// speed or after a DVFS change of the clock
+    ///
+    /// Owns the bus like `lock_bus`, so no transfer is in progress. Once
+    /// the bus is idle the timings are computed again and the controller
+    /// is reprogrammed before the bus is released. On error the previous
+    /// configuration stays in place.
+    pub fn reconfigure(&self, timing: I2cTiming, clk_rate_khz: u32) -> Result<()> {
+        let _guard = self.lock_bus();
+        self.check_resumed()?;
+        self.acquire_bus_semaphore()?;
+        let ret = self.reconfigure_owned(timing, clk_rate_khz);
+        self.release_bus_semaphore();
+        ret
+    }
+
+    fn reconfigure_owned(&self, timing: I2cTiming, clk_rate_khz: u32) -> Result<()> {
+        self.pm_get()?;
+        let ret = self.reconfigure_powered(timing, clk_rate_khz);
+        self.pm_put();
+        ret
+    }
+
+    fn reconfigure_powered(&self, timing: I2cTiming, clk_rate_khz: u32) -> Result<()> {
+        self.driver.wait_bus_not_busy()?;
+
+        let timing = match self.timing_init(DwI2cBusTiming::new(&timing, clk_rate_khz)) {
+            Ok(timing) => timing,
+            Err(e) => {
+                log_err!("{:?} while reconfiguring, keep the previous timing", e);
+                return Err(e);
+            }
+        };
+        *self.timing.lock() = timing;
+
+        self.master_setup();
+        self.driver.disable_all_interrupt();
+        self.idle();
+        Ok(())
+    }
+
+    /// Compute the timings of `bus`
+    fn timing_init(&self, mut bus: DwI2cBusTiming) -> Result<MasterTiming> {
+        self.driver.speed_check(&mut bus)?;
+        let lhcnt = self.scl_lhcnt_init(&bus)?;
+        self.driver.sda_hold_time_init(&mut bus)?;
+        self.driver.smbus_cnt_init(&mut bus)?;
+        Ok(MasterTiming { bus, lhcnt })
+    }
+
+    /// Prepare the adapter for a system suspend which may power it off
+    ///
+    /// Waits for the transfer in progress and for the bus to be idle,
+    /// then disables the controller. Transfers fail with `Errno::Busy`
+    /// until `resume`.
+    pub fn suspend(&self) -> Result<()> {
+        let _guard = self.lock_bus();
+        if self.is_suspended() {
+            return Ok(());
+        }
+
+        // turned off by runtime PM, it replays the configuration itself
+        if !self.is_powered() {
+            self.suspended.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        self.acquire_bus_semaphore()?;
+        let ret = self.suspend_owned();
+        self.release_bus_semaphore();
+        ret?;
+        self.suspended.store(true, Ordering::Release);
+        log_info!("i2c dw suspended");
+        Ok(())
+    }
+
+    fn suspend_owned(&self) -> Result<()> {
+        self.driver.wait_bus_not_busy()?;
+        self.disable(false);
+        self.driver.disable_all_interrupt();
+        self.driver.write_smbus_interrupt_mask(&LocalRegisterCopy::new(0));
+        Ok(())
+    }
+
+    /// Bring the adapter back after `suspend`
+    ///
+    /// Checks IC_COMP_TYPE, then replays the cached configuration of
+    /// `setup` into the registers, owning the bus like `suspend`.
+    pub fn resume(&self) -> Result<()> {
+        let _guard = self.lock_bus();
+        if !self.is_suspended() {
+            return Ok(());
+        }
+
+        if !self.is_powered() {
+            self.suspended.store(false, Ordering::Release);
+            return Ok(());
+        }
+
+        self.acquire_bus_semaphore()?;
+        let ret = self.resume_owned();
+        self.release_bus_semaphore();
+        ret?;
+        self.suspended.store(false, Ordering::Release);
+        log_info!("i2c dw resumed");
+        Ok(())
+    }
+
+    fn resume_owned(&self) -> Result<()> {
+        self.driver.com_type_verify()?;
+        self.master_setup();
+        self.driver.disable_all_interrupt();
+        self.idle();
+        Ok(())
+    }
+
+    /// Whether the adapter is suspended
+    #[inline]
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire)
+    }
+
+    /// Turn the block on and off through `hooks` around transfers, and
+    /// off after `autosuspend_delay_us` of idle time, see `runtime_idle`
+    ///
+    /// Must be called before `setup`. The block is kept on while a Host
+    /// Notify or SMBus alert handler is registered, or SMBSUS# is
+    /// asserted, as it would miss the events or release SMBSUS# when off.
+    pub fn set_platform_hooks(
+        &mut self,
+        hooks: &'static dyn I2cDwPlatformHooks,
+        autosuspend_delay_us: u64,
+    ) {
+        self.pm_hooks = Some(hooks);
+        self.autosuspend_delay_us = autosuspend_delay_us;
+    }
+
+    /// Acquire `semaphore` before each transfer or other programming of
+    /// the controller and release it after, for a controller shared with
+    /// firmware
+    ///
+    /// Transfers fail with the error of `acquire` if the semaphore is not
+    /// granted within `timeout_us`, 0 for the default of 500 ms. Host
+    /// Notify keeps the adapter enabled between transfers, behind the
+    /// back of the firmware, so the two are exclusive and this fails with
+    /// `Errno::NotSupported` while a Host Notify handler is enabled.
+    pub fn set_bus_semaphore(
+        &mut self,
+        semaphore: &'static dyn I2cDwBusSemaphore,
+        timeout_us: u64,
+    ) -> Result<()> {
+        if self.smbus.lock().host_notify_handler.is_some() {
+            log_err!("a bus semaphore can not be used with Host Notify");
+            return to_error(Errno::NotSupported);
+        }
+        self.bus_semaphore = Some(semaphore);
+        self.semaphore_timeout_us = match timeout_us {
+            0 => DW_I2C_SEMAPHORE_TIMEOUT_US,
+            _ => timeout_us,
+        };
+        Ok(())
+    }
+
+    /// Take `bus_semaphore`, under the bus lock
+    ///
+    /// Nested calls only count, so a path programming the controller can
+    /// take it whether its caller holds it or not.
+    fn acquire_bus_semaphore(&self) -> Result<()> {
+        if let Some(semaphore) = self.bus_semaphore {
+            if self.semaphore_depth.load(Ordering::Relaxed) == 0 {
+                if let Err(e) = semaphore.acquire(self.semaphore_timeout_us) {
+                    log_err!("{:?} while acquiring the bus semaphore", e);
+                    return Err(e);
+                }
+            }
+            self.semaphore_depth.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn release_bus_semaphore(&self) {
+        if let Some(semaphore) = self.bus_semaphore {
+            if self.semaphore_depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+                semaphore.release();
+            }
+        }
+    }
+
+    /// Turn the block off if it stayed idle for the autosuspend delay,
+    /// return whether it is off
+    ///
+    /// There is no timer in the driver, call this periodically, e.g. from
+    /// a timer armed with the autosuspend delay after each transfer.
+    pub fn runtime_idle(&self) -> bool {
+        if self.pm_hooks.is_none() || self.keep_powered() {
+            return false;
+        }
+
+        let _guard = match self.try_lock_bus() {
+            Some(guard) => guard,
+            None => return false,
+        };
+        if self.is_powered() &&
+            self.pm_usage.load(Ordering::Relaxed) == 0 &&
+            osl::time::current_time() > self.autosuspend_deadline.load(Ordering::Relaxed)
+        {
+            self.power_off();
+        }
+        !self.is_powered()
+    }
+
+    /// Whether the block is clocked and out of reset
+    #[inline]
+    pub fn is_powered(&self) -> bool {
+        self.powered.load(Ordering::Acquire)
+    }
+
+    fn power_on(hooks: &dyn I2cDwPlatformHooks) -> Result<()> {
+        hooks.clk_enable()?;
+        if let Err(e) = hooks.reset_deassert() {
+            hooks.clk_disable();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Make sure the block is on for a transfer, under the bus lock,
+    /// paired with `pm_put`
+    fn pm_get(&self) -> Result<()> {
+        let hooks = match self.pm_hooks {
+            Some(hooks) => hooks,
+            None => return Ok(()),
+        };
+        if !self.is_powered() {
+            self.power_up(hooks)?;
+        }
+        self.pm_usage.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn power_up(&self, hooks: &dyn I2cDwPlatformHooks) -> Result<()> {
+        self.acquire_bus_semaphore()?;
+        let ret = self.power_up_owned(hooks);
+        self.release_bus_semaphore();
+        ret
+    }
+
+    fn power_up_owned(&self, hooks: &dyn I2cDwPlatformHooks) -> Result<()> {
+        Self::power_on(hooks)?;
+        let clk_rate_khz = hooks.clk_rate_khz();
+        let mut bus = self.timing.lock().bus.clone();
+        if clk_rate_khz != 0 && clk_rate_khz != bus.clk_rate_khz {
+            log_info!("ic clk changed to {} kHz, computing the timings again", clk_rate_khz);
+            bus.clk_rate_khz = clk_rate_khz;
+            match self.timing_init(bus) {
+                Ok(timing) => *self.timing.lock() = timing,
+                Err(e) => log_err!("{:?} at the new ic clk, keep the previous timing", e),
+            }
+        }
+
+        // out of reset all registers are back at their defaults
+        self.master_setup();
+        self.driver.disable_all_interrupt();
+        self.idle();
+        self.powered.store(true, Ordering::Release);
+        log_debug!("i2c dw runtime resumed");
+        Ok(())
+    }
+
+    /// Drop the reference taken by `pm_get`, under the bus lock, and arm
+    /// the autosuspend delay after the last one
+    fn pm_put(&self) {
+        if self.pm_hooks.is_none() {
+            return;
+        }
+        if self.pm_usage.fetch_sub(1, Ordering::Relaxed) != 1 || self.keep_powered() {
+            return;
+        }
+
+        if self.autosuspend_delay_us == 0 {
+            self.power_off();
+        } else {
+            let deadline = osl::time::time_add_us(self.autosuspend_delay_us);
+            self.autosuspend_deadline.store(deadline, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the block must stay on between transfers, to detect Host
+    /// Notify and SMBALERT# or to keep SMBSUS# asserted
+    fn keep_powered(&self) -> bool {
+        let smbus = self.smbus.lock();
+        smbus.host_notify_handler.is_some() ||
+            !smbus.alert_handlers.is_empty() ||
+            self.smbus_suspended.load(Ordering::Acquire)
+    }
+
+    /// Turn the block off, under the bus lock
+    fn power_off(&self) {
+        let hooks = match self.pm_hooks {
+            Some(hooks) => hooks,
+            None => return,
+        };
+
+        // stay on without the semaphore, runtime_idle tries again
+        if self.acquire_bus_semaphore().is_err() {
+            return;
+        }
+        self.disable(false);
+        self.driver.disable_all_interrupt();
+        self.release_bus_semaphore();
+        self.powered.store(false, Ordering::Release);
+        hooks.reset_assert();
+        hooks.clk_disable();
+        log_debug!("i2c dw runtime suspended");
+    }
+
+    fn check_resumed(&self) -> Result<()> {
+        if self.is_suspended() {
+            log_err!("transfer while suspended");
+            return to_error(Errno::Busy);
+        }
         Ok(())
     }
 
+    /// Return what the adapter can not do
+    pub fn quirks(&self) -> I2cDwAdapterQuirks {
+        DW_I2C_QUIRKS
+    }
+
     /// return  i2c functionality
     pub fn get_functionality(&self) -> I2cFuncFlags {
         self.driver.functionality
     }
 
     /// Prepare controller for a transaction and call xfer_msg
+    ///
+    /// Takes the bus lock for the transfer, see `lock_bus`.
     pub fn master_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        self.lock_bus().master_transfer(msgs)
+    }
+
+    /// Like `master_transfer`, but hand `msgs` back, see
+    /// `I2cDwBusGuard::master_transfer_msgs`
+    pub fn master_transfer_msgs(
+        &self,
+        msgs: Vec<I2cMsg>,
+        xfer_len: &mut [usize],
+    ) -> (Vec<I2cMsg>, Result<i32>) {
+        self.lock_bus().master_transfer_msgs(msgs, xfer_len)
+    }
+
+    /// Set the retry policy of transfers which lose arbitration, from
+    /// the next transfer on
+    ///
+    /// The whole msg list is re-run. To do so `I2cMsg`s are copied into
+    /// buffers of the driver first; if they can't be allocated the
+    /// transfer runs once, without retries. Use
+    /// `I2cDwBusGuard::retries_used` to read the retries of a transfer.
+    pub fn set_retry_policy(&self, policy: I2cDwRetryPolicy) {
+        self.retries_max.store(policy.retries, Ordering::Relaxed);
+        self.retry_backoff_us.store(policy.backoff_us, Ordering::Relaxed);
+    }
+
+    /// Transfer borrowed `ops` to `addr`, see
+    /// `I2cDwBusGuard::master_transfer_ops`
+    pub fn master_transfer_ops(
+        &self,
+        addr: u16,
+        ten_bit: bool,
+        ops: &mut [Operation<'_>],
+    ) -> Result<i32> {
+        self.lock_bus().master_transfer_ops(addr, ten_bit, ops)
+    }
+
+    /// Send `msgs` to the General Call address 0x00, see
+    /// `I2cDwBusGuard::general_call_transfer`
+    pub fn general_call_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        self.lock_bus().general_call_transfer(msgs)
+    }
+
+    /// Transfer `msgs` prefixed by a START byte, see
+    /// `I2cDwBusGuard::start_byte_transfer`
+    pub fn start_byte_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        self.lock_bus().start_byte_transfer(msgs)
+    }
+
+    /// Abort the transfer in flight, callable from another context such
+    /// as a watchdog or the drop of a cancelled future
+    ///
+    /// The controller stops after the current byte with a STOP and its
+    /// FIFOs are flushed. The waiting transfer returns `Errno::Canceled`
+    /// and the adapter is ready for the next one. Return
+    /// `Errno::InvalidArgs` if no transfer is in flight.
+    pub fn abort_transfer(&self) -> Result<()> {
+        // Between transfers the adapter is disabled, or kept enabled for
+        // Host Notify with all I2C interrupts masked. Don't take the xfer
+        // lock here, the irq handler may need it to finish the transfer.
+        if self.is_suspended()
+            || !self.is_powered()
+            || !self.driver.ic_enable().is_set(IC_ENABLE::ENABLE)
+            || self.driver.interrupt_mask().get() == 0
+        {
+            return to_error(Errno::InvalidArgs);
+        }
+
+        log_warn!("aborting transfer");
+        if self.driver.abort(ABORT_TIMEOUT_US).is_ok() {
+            // TX_ABRT completes the waiter through the irq handler
+            return Ok(());
+        }
+
+        // The controller did not acknowledge the abort, complete the
+        // waiter ourselves. With the interrupts masked the irq handler
+        // leaves the state machine alone, so the lock is safe.
+        self.driver.disable_all_interrupt();
+        let mut transfer = self.xfer.lock();
+        if transfer.is_active() {
+            transfer.cmd_err |= DwI2cCmdErr::USER_ABRT;
+            transfer.status = DwI2cStatus::empty();
+            drop(transfer);
+            self.cmd_complete.complete();
+        }
+        Ok(())
+    }
+
+    /// Take exclusive ownership of the adapter, like Linux `i2c_lock_bus`
+    ///
+    /// Other callers block until the returned guard is dropped or passed
+    /// to `unlock_bus`, so a sequence of transfers done through the guard
+    /// is not interleaved with theirs. Calling the locking transfer APIs
+    /// of the driver while holding the guard deadlocks.
+    pub fn lock_bus(&self) -> I2cDwBusGuard<'_, A> {
+        self.bus_lock.lock();
+        I2cDwBusGuard { master: self }
+    }
+
+    /// Like `lock_bus`, but return None if the adapter is owned
+    pub fn try_lock_bus(&self) -> Option<I2cDwBusGuard<'_, A>> {
+        if self.bus_lock.try_lock() {
+            Some(I2cDwBusGuard { master: self })
+        } else {
+            None
+        }
+    }
+
+    /// Release the adapter taken by `lock_bus`
+    pub fn unlock_bus(&self, guard: I2cDwBusGuard<'_, A>) {
+        drop(guard);
+    }
+
+    fn xfer_msgs(&self, msgs: Vec<I2cMsg>, tar_mode: TarMode) -> Result<i32> {
+        self.xfer(XferMsgs::Msgs(msgs), tar_mode)
+    }
+
+    fn xfer_ops(&self, addr: u16, ten_bit: bool, ops: &mut [Operation<'_>]) -> Result<i32> {
+        let addr_max = if ten_bit { 0x3ff } else { 0x7f };
+        if addr > addr_max || ops.is_empty() || ops.iter().any(|op| op.is_empty()) {
+            log_err!("invalid transfer to 0x{:x} of {} operations", addr, ops.len());
+            return to_error(Errno::InvalidArgs);
+        }
+
+        let _borrow = OpsBorrow { master: self };
+        self.xfer(XferMsgs::from_ops(addr, ten_bit, ops), TarMode::Normal)
+    }
+
+    /// Whether `ret` failed because the transfer lost arbitration
+    fn lost_arbitration(&self, ret: &Result<i32>) -> bool {
+        // the transfer is over and interrupt is disabled,
+        // so here lock is safety
+        matches!(ret, Err(Errno::Again)) &&
+            MasterXfer::arbitration_lost(self.xfer.lock().abort_source)
+    }
+
+    fn xfer(&self, msgs: XferMsgs, tar_mode: TarMode) -> Result<i32> {
         let msg_num = msgs.len();
+        // called with the bus lock held, so the count is our own
+        self.retries_used.store(0, Ordering::Relaxed);
+
+        // no transfer in flight, interrupt is disabled
+        // so here lock is safety. The msgs are stored before anything
+        // can fail, so `master_transfer_msgs` always hands them back
+        let mut transfer = self.xfer.lock();
+        transfer.init(msgs);
+        let mut ret = self.check_resumed();
+        if ret.is_ok() {
+            ret = transfer.check_quirks(&DW_I2C_QUIRKS);
+        }
+        let retries_max = self.retries_max.load(Ordering::Relaxed);
+        if ret.is_ok() && retries_max > 0 {
+            // a RECV_LEN read gets the length byte, the block and PEC.
+            // Retrying is best effort, without the copy the msgs run once
+            let recv_len_max = usize::from(DW_I2C_QUIRKS.max_recv_len) + 2;
+            if let Err(e) = transfer.msgs.stage(recv_len_max) {
+                log_warn!("{:?} while staging the msgs, no retry", e);
+            }
+        }
+        drop(transfer);
+        ret?;
+
+        // before any register access, powering up programs the controller
+        self.acquire_bus_semaphore()?;
+        let ret = self.xfer_owned(tar_mode, msg_num, retries_max);
+        self.release_bus_semaphore();
+        ret
+    }
+
+    fn xfer_owned(&self, tar_mode: TarMode, msg_num: usize, retries_max: u32) -> Result<i32> {
+        self.pm_get()?;
+        let ret = self.xfer_powered(tar_mode, msg_num, retries_max);
+        self.pm_put();
+        ret
+    }
+
+    fn xfer_powered(&self, tar_mode: TarMode, msg_num: usize, retries_max: u32) -> Result<i32> {
+        let mut retries = 0;
+        let ret = loop {
+            let ret = self.xfer_attempt(tar_mode, msg_num);
+            // the transfer is over and interrupt is disabled,
+            // so here lock is safety
+            if retries >= retries_max || !self.lost_arbitration(&ret) ||
+                !self.xfer.lock().restart()
+            {
+                break ret;
+            }
+
+            retries += 1;
+            log_warn!("arbitration lost, retry {}/{}", retries, retries_max);
+            // the next attempt waits for the other master to release
+            // the bus, with the block powered and the semaphore held
+            usleep(self.retry_backoff_us.load(Ordering::Relaxed));
+        };
+        self.retries_used.store(retries, Ordering::Relaxed);
+        ret
+    }
+
+    fn xfer_attempt(&self, tar_mode: TarMode, msg_num: usize) -> Result<i32> {
+        self.in_flight.store(true, Ordering::Release);
+        let ret = self.xfer_in_flight(tar_mode, msg_num);
+        self.in_flight.store(false, Ordering::Release);
+        ret
+    }
+
+    fn xfer_in_flight(&self, tar_mode: TarMode, msg_num: usize) -> Result<i32> {
+        // transfer exit make sure interrupt is disabled
+        // so here lock is safety, borrowed ops wait without allocating
+        let poll = self.xfer.lock().msgs.is_borrowed();
         // reinit complete
-        self.cmd_complete.reinit();
+        let waiter = self.cmd_complete.reinit(poll);
         // wait bus free
         self.driver.wait_bus_not_busy()?;
         // transfer exit make sure interrupt is disabled 
         // so here lock is safety
         let mut transfer = self.xfer.lock();
-        transfer.prepare(msgs, &self);
+        transfer.prepare(tar_mode, &self);
         drop(transfer);
         // Now, could enable interrupt
         self.driver.clear_all_interrupt();
         self.driver.write_interrupt_mask(&Self::master_default_intr_mask());
 
         // wait transfer complete
-        match self.cmd_complete.wait_for_completion_timeout(1) {
+        match self.cmd_complete.wait(waiter) {
             Err(e) => {
                 log_err!("wait complete timeout");
+                // with the interrupts masked the lock is safe, the
+                // commands left in the TX FIFO never made it to the bus
+                self.driver.disable_all_interrupt();
+                let flushed = self.driver.ic_txflr().get() as usize;
+                let mut transfer = self.xfer.lock();
+                transfer.clear_active();
+                transfer.account_xfer_len(flushed);
+                drop(transfer);
                 //master_setup implicitly disables the adapter
                 self.master_setup();
                 self.driver.clear_all_interrupt();
-                self.driver.disable_all_interrupt();
+                self.idle();
                 return Err(e);
             }
             Ok(_) => (),
@@ -541,17 +1544,44 @@ impl I2cDwMasterDriver {
         // complete make sure interrupt is disable 
         // so here lock is safety
         let mut transfer = self.xfer.lock();
-        transfer.exit(&self)?;
+        let ret = transfer.exit(&self);
+        let clk_extnd_timeout = transfer.cmd_err.contains(DwI2cCmdErr::CLK_EXTND_TIMEOUT);
+        drop(transfer);
+        if clk_extnd_timeout {
+            self.smbus_recover();
+        }
+        self.idle();
+        ret?;
         Ok(msg_num.try_into().unwrap())
     }
     
     /// Interrupt service routine. This gets called whenever an I2C master interrupt
     /// occurs
     pub fn irq_handler(&self) -> irq::Return {
+        // the registers may be powered off
+        if self.is_suspended() || !self.is_powered() {
+            return to_irq_return(ReturnEnum::None);
+        }
+
+        // SMBus interrupts are also raised while the adapter is disabled
+        // between transfers, so handle them before the enable check
+        let smbus_handled = self.smbus_irq_process();
+        // Only SMBus interrupts pending, e.g. while the adapter is kept
+        // enabled for Host Notify, nothing for the transfer state machine
+        if smbus_handled && self.driver.ic_intr_stat().get() == 0 {
+            return to_irq_return(ReturnEnum::Handled);
+        }
+
+        // Check the masked status: between transfers the controller may
+        // be kept enabled for Host Notify or a polled read, with raw
+        // TX_EMPTY set, and a shared irq line must not reach the state
+        // machine then
         let enable = self.driver.ic_enable();
-        let stat = self.driver.ic_raw_intr_stat().get();
-        // check raw intr stat
-        if !enable.is_set(IC_ENABLE::ENABLE) || (stat & !0b100000000) == 0 {
+        let stat = self.driver.ic_intr_stat().get();
+        if !enable.is_set(IC_ENABLE::ENABLE) || stat == 0 {
+            if smbus_handled {
+                return to_irq_return(ReturnEnum::Handled);
+            }
             return to_irq_return(ReturnEnum::None);
         }
 
@@ -559,11 +1589,16 @@ impl I2cDwMasterDriver {
         // no longer lock transfer, so here lock is safety
         log_debug!("enter irq stat: {:x}, enable: {:x}", stat, enable.get());
         let mut transfer = self.xfer.lock();
-        let result = transfer.irq_process(&self);
+        let result = if transfer.is_active() {
+            transfer.irq_process(&self)
+        } else {
+            TransferResult::UnExpectedInterrupt
+        };
         drop(transfer);
 
         match result {
             TransferResult::UnExpectedInterrupt => {
+                log_warn!("unexpected irq stat: {:x}", stat);
                 self.driver.disable_all_interrupt();
             },
             TransferResult::Abort => {
@@ -581,16 +1616,27 @@ impl I2cDwMasterDriver {
     }
 
     fn master_setup(&self) {
+        // copy the timing out, disabling the adapter sleeps
+        let timing = self.timing.lock().clone();
         // Disable the adapter
         self.disable(false);
         // Write standard speed timing parameters
-        self.driver.write_lhcnt(&self.lhcnt);
+        self.driver.write_lhcnt(&timing.lhcnt, timing.bus.speed_mode);
         // Write SDA hold time if supported
-        self.driver.write_sda_hold_time();
+        self.driver.write_sda_hold_time(&timing.bus);
+        // Write SMBus timeouts if SMBus compliance mode is on
+        self.driver.write_smbus_cnt(&timing.bus);
         // Write fifo
-        self.driver.write_fifo(self.tx_fifo_depth / 2, 0);
+        self.driver.write_fifo(self.tx_tl, 0);
         // set IC_CON
-        self.driver.write_ic_con(&self.cfg);
+        let mut cfg = self.cfg;
+        self.driver.cfg_init_speed(&timing.bus, &mut cfg);
+        self.driver.write_ic_con(&cfg);
+        // SMBSUS# is lost with a reset of the block
+        self.driver.enable_smbus_suspend(self.smbus_suspended.load(Ordering::Acquire));
+        // Write SMBus interrupt mask
+        let smbus_intr_mask = self.smbus.lock().intr_mask;
+        self.driver.write_smbus_interrupt_mask(&smbus_intr_mask);
     }
 
     fn disable(&self, fast: bool) {
@@ -601,22 +1647,42 @@ impl I2cDwMasterDriver {
         }
     }
 
-    fn fifo_size_init(&mut self) {
-        let com_param_1 = self.driver.ic_comp_param_1();
-        self.tx_fifo_depth = com_param_1.read(IC_COMP_PARAM_1::TX_BUFFER_DEPTH) + 1;
-        self.rx_fifo_depth = com_param_1.read(IC_COMP_PARAM_1::RX_BUFFER_DEPTH) + 1;
+    fn fifo_size_init(&mut self) -> Result<()> {
+        // setup probes first
+        let hw_params = match self.driver.hw_params {
+            Some(hw_params) => hw_params,
+            None => {
+                log_err!("fifo depth read before probe");
+                return to_error(Errno::NoSuchDevice);
+            }
+        };
+        self.tx_fifo_depth = hw_params.tx_fifo_depth;
+        self.rx_fifo_depth = hw_params.rx_fifo_depth;
         log_info!(
             "I2C fifo_depth RX:TX = {}: {}",
             self.rx_fifo_depth,
             self.tx_fifo_depth
         );
+
+        let thresholds = self.driver.ext_config.fifo_thresholds;
+        let tx_tl = thresholds.tx_tl.unwrap_or(self.tx_fifo_depth / 2);
+        let rx_tl_max = match thresholds.rx {
+            I2cDwRxThreshold::Fixed(rx_tl) => rx_tl,
+            I2cDwRxThreshold::Adaptive => (self.rx_fifo_depth / 2).saturating_sub(1),
+        };
+        if tx_tl >= self.tx_fifo_depth || rx_tl_max >= self.rx_fifo_depth {
+            log_warn!("fifo thresholds tx:rx {}:{} beyond the fifo depth", tx_tl, rx_tl_max);
+        }
+        self.tx_tl = tx_tl.min(self.tx_fifo_depth - 1);
+        self.rx_tl_max = rx_tl_max.min(self.rx_fifo_depth - 1);
+        Ok(())
     }
 
-    fn scl_lhcnt_init(&mut self) -> Result<()> {
-        let driver = &mut self.driver;
-        let ic_clk = driver.ext_config.clk_rate_khz;
-        let mut scl_fall_ns = driver.ext_config.timing.get_scl_fall_ns();
-        let mut sda_fall_ns = driver.ext_config.timing.get_sda_fall_ns();
+    fn scl_lhcnt_init(&self, bus: &DwI2cBusTiming) -> Result<DwI2cSclLHCnt> {
+        let mut lhcnt = DwI2cSclLHCnt::default();
+        let ic_clk = bus.clk_rate_khz;
+        let mut scl_fall_ns = bus.scl_fall_ns;
+        let mut sda_fall_ns = bus.sda_fall_ns;
 
         // Set standard and fast speed dividers for high/low periods
         if scl_fall_ns == 0 {
@@ -628,47 +1694,454 @@ impl I2cDwMasterDriver {
         }
 
         // tLOW = 4.7 us and no offset
-        self.lhcnt.ss_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 4700, scl_fall_ns, 0) as u16;
+        lhcnt.ss_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 4700, scl_fall_ns, 0) as u16;
         // tHigh = 4 us and no offset DW default
-        self.lhcnt.ss_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 4000, sda_fall_ns, false, 0) as u16;
+        lhcnt.ss_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 4000, sda_fall_ns, false, 0) as u16;
         log_info!(
             "I2C dw Standard Mode HCNT:LCNT = {} : {}",
-            self.lhcnt.ss_hcnt,
-            self.lhcnt.ss_lcnt
+            lhcnt.ss_hcnt,
+            lhcnt.ss_lcnt
         );
 
-        let speed_mode = driver.speed_mode;
+        let speed_mode = bus.speed_mode;
         if speed_mode == I2cSpeedMode::FastPlusMode {
-            self.lhcnt.fs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 500, scl_fall_ns, 0) as u16;
-            self.lhcnt.fs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 260, sda_fall_ns, false, 0) as u16;
+            lhcnt.fs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 500, scl_fall_ns, 0) as u16;
+            lhcnt.fs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 260, sda_fall_ns, false, 0) as u16;
             log_info!(
                 "I2C Fast Plus Mode HCNT:LCNT = {} : {}",
-                self.lhcnt.fs_hcnt,
-                self.lhcnt.fs_lcnt
+                lhcnt.fs_hcnt,
+                lhcnt.fs_lcnt
             );
         } else {
-            self.lhcnt.fs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 1300, scl_fall_ns, 0) as u16;
-            self.lhcnt.fs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 600, sda_fall_ns, false, 0) as u16;
+            lhcnt.fs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 1300, scl_fall_ns, 0) as u16;
+            lhcnt.fs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 600, sda_fall_ns, false, 0) as u16;
             log_info!(
                 "I2C Fast Mode HCNT:LCNT = {} : {}",
-                self.lhcnt.fs_hcnt,
-                self.lhcnt.fs_lcnt
+                lhcnt.fs_hcnt,
+                lhcnt.fs_lcnt
             );
         }
 
         if speed_mode == I2cSpeedMode::HighSpeedMode {
-            self.lhcnt.hs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, 320, scl_fall_ns, 0) as u16;
-            self.lhcnt.hs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, 160, sda_fall_ns, false, 0) as u16;
+            // tLOW:tHIGH = 320:160 ns at 400 pF, 120:60 ns at 100 pF
+            let (t_low, t_high) = match self.driver.ext_config.bus_capacitance_pf {
+                100 => (120, 60),
+                _ => (320, 160),
+            };
+            lhcnt.hs_lcnt = DwI2cSclLHCnt::scl_lcnt(ic_clk, t_low, scl_fall_ns, 0) as u16;
+            lhcnt.hs_hcnt = DwI2cSclLHCnt::scl_hcnt(ic_clk, t_high, sda_fall_ns, false, 0) as u16;
             log_info!(
                 "I2C High Speed Mode HCNT:LCNT = {} : {}",
-                self.lhcnt.hs_hcnt,
-                self.lhcnt.hs_lcnt
+                lhcnt.hs_hcnt,
+                lhcnt.hs_lcnt
             );
         }
 
+        Ok(lhcnt)
+    }
+
+    /// Register a handler for SMBus alerts signalled by the device at
+    /// the 7-bit address `addr`
+    ///
+    /// Registering the first handler enables the SMBus alert interrupt.
+    /// When an alert is signalled, `smbus_alert_process` must be called
+    /// from thread context to identify the device and run its handler.
+    /// Runtime PM keeps the block on while a handler is registered.
+    pub fn register_smbus_alert_handler(
+        &self,
+        addr: u16,
+        handler: SmbusAlertHandler,
+    ) -> Result<()> {
+        let mut smbus = self.smbus.lock();
+        smbus.alert_handlers.register(addr, handler)?;
+        smbus.intr_mask.modify(IC_SMBUS_INTR::SMBUS_ALERT_DET.val(1));
+        drop(smbus);
+        if let Err(e) = self.update_smbus_interrupt_mask() {
+            log_err!("{:?} while enabling SMBus alerts", e);
+            let mut smbus = self.smbus.lock();
+            let _ = smbus.alert_handlers.unregister(addr);
+            if smbus.alert_handlers.is_empty() {
+                smbus.intr_mask.modify(IC_SMBUS_INTR::SMBUS_ALERT_DET.val(0));
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Unregister the SMBus alert handler of `addr`
+    pub fn unregister_smbus_alert_handler(&self, addr: u16) -> Result<()> {
+        let mut smbus = self.smbus.lock();
+        smbus.alert_handlers.unregister(addr)?;
+        if !smbus.alert_handlers.is_empty() {
+            return Ok(());
+        }
+        smbus.intr_mask.modify(IC_SMBUS_INTR::SMBUS_ALERT_DET.val(0));
+        drop(smbus);
+        self.update_smbus_interrupt_mask()
+    }
+
+    /// Write the SMBus interrupt mask and put the adapter in its idle state,
+    /// turning the block on if `keep_powered` now asks for it
+    fn update_smbus_interrupt_mask(&self) -> Result<()> {
+        let _bus = self.lock_bus();
+        // master_setup writes the mask once the block is back
+        if self.is_suspended() || (!self.is_powered() && !self.keep_powered()) {
+            return Ok(());
+        }
+
+        self.acquire_bus_semaphore()?;
+        let ret = self.pm_get();
+        if ret.is_ok() {
+            let smbus_intr_mask = self.smbus.lock().intr_mask;
+            self.driver.write_smbus_interrupt_mask(&smbus_intr_mask);
+            if smbus_intr_mask.is_set(IC_SMBUS_INTR::HOST_NOTIFY_MST_DET) {
+                self.idle();
+            } else {
+                self.disable(false);
+            }
+            self.pm_put();
+        }
+        self.release_bus_semaphore();
+        ret
+    }
+
+    /// Return true if SMBALERT# was signalled since the last
+    /// `smbus_alert_process`
+    #[inline]
+    pub fn smbus_alert_pending(&self) -> bool {
+        self.smbus_alert_pending.load(Ordering::Acquire)
+    }
+
+    /// Identify the device(s) signalling SMBALERT# and dispatch to their
+    /// handlers, return the number of alerts handled
+    ///
+    /// Reads the Alert Response Address until SMBALERT# is released, so it
+    /// performs bus transfers and must not be called from the irq handler.
+    /// The handlers run once the bus is released, so they can transfer to
+    /// the device which alerted.
+    pub fn smbus_alert_process(&self) -> Result<usize> {
+        self.smbus_alert_pending.store(false, Ordering::Release);
+
+        let mut alerts = [(0u16, 0u8); SMBUS_ALERT_MAX_LOOPS];
+        let bus = self.lock_bus();
+        // the alert stays masked, master_setup re-arms it once the block
+        // is back
+        self.check_resumed()?;
+        self.acquire_bus_semaphore()?;
+        let (found, ret) = self.smbus_alert_process_owned(&mut alerts);
+        self.release_bus_semaphore();
+        drop(bus);
+
+        for &(addr, flag) in &alerts[..found] {
+            let handler = self.smbus.lock().alert_handlers.find(addr);
+            match handler {
+                Some(handler) => handler(addr, flag),
+                None => log_warn!("no handler for SMBus alert from 0x{:x}", addr),
+            }
+        }
+        ret.map(|_| found)
+    }
+
+    fn smbus_alert_process_owned(
+        &self,
+        alerts: &mut [(u16, u8); SMBUS_ALERT_MAX_LOOPS],
+    ) -> (usize, Result<()>) {
+        if let Err(e) = self.pm_get() {
+            return (0, Err(e));
+        }
+        let ret = self.smbus_alert_process_powered(alerts);
+        // Re-arm the alert interrupt masked by the irq handler
+        let smbus_intr_mask = self.smbus.lock().intr_mask;
+        self.driver.write_smbus_interrupt_mask(&smbus_intr_mask);
+        self.pm_put();
+        ret
+    }
+
+    /// Read the ARA into `alerts` until SMBALERT# is released, return the
+    /// number of (address, flag) answers read, also on error
+    fn smbus_alert_process_powered(
+        &self,
+        alerts: &mut [(u16, u8); SMBUS_ALERT_MAX_LOOPS],
+    ) -> (usize, Result<()>) {
+        let mut found = 0;
+        let mut ret = Ok(());
+        for _ in 0..SMBUS_ALERT_MAX_LOOPS {
+            if !self.driver.ic_status().is_set(IC_STATUS::SMBUS_ALERT_STATUS) {
+                break;
+            }
+
+            // The device with the lowest address wins the arbitration
+            // and answers with its address and a flag bit.
+            let mut status = [0u8; 1];
+            if let Err(e) = self.smbus_ara_read(&mut status) {
+                log_err!("{:?} while reading SMBus alert response address", e);
+                ret = Err(e);
+                break;
+            }
+            let addr = (status[0] >> 1) as u16;
+            let flag = status[0] & 0x1;
+            log_debug!("SMBus alert from 0x{:x}, flag {}", addr, flag);
+
+            // A device which does not release SMBALERT# after answering
+            // would keep winning the arbitration, stop here.
+            if found > 0 && alerts[found - 1].0 == addr {
+                log_warn!("SMBus alert from 0x{:x} is stuck", addr);
+                break;
+            }
+            alerts[found] = (addr, flag);
+            found += 1;
+        }
+        (found, ret)
+    }
+
+    /// Read the Device ID of the target at the 7-bit address `addr`
+    ///
+    /// The controller sends the Device ID address with `addr`, then a
+    /// repeated START to read the three Device ID bytes. Targets that do
+    /// not support Device ID NACK and `Errno::Io` is returned.
+    pub fn read_device_id(&self, addr: u16) -> Result<I2cDeviceId> {
+        if addr > 0x7f {
+            log_err!("Device ID read needs a 7-bit address, got 0x{:x}", addr);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        let mut ic_tar: LocalRegisterCopy<u32, IC_TAR::Register> = LocalRegisterCopy::new(0);
+        ic_tar.modify(IC_TAR::TAR.val(addr.into()));
+        ic_tar.modify(IC_TAR::DEVICE_ID.val(0b1));
+
+        let mut buf = [0u8; 3];
+        let bus = self.lock_bus();
+        self.polled_read(&ic_tar, &mut buf)?;
+        drop(bus);
+        let raw = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
+        log_debug!("Device ID of 0x{:x}: {:x}", addr, raw);
+        Ok(I2cDeviceId::from_raw(raw))
+    }
+
+    fn smbus_ara_read(&self, buf: &mut [u8]) -> Result<()> {
+        let mut ic_tar: LocalRegisterCopy<u32, IC_TAR::Register> = LocalRegisterCopy::new(0);
+        ic_tar.modify(IC_TAR::TAR.val(SMBUS_ARA_ADDR.into()));
+        self.polled_read(&ic_tar, buf)
+    }
+
+    /// Run a short polled read with `ic_tar` outside the interrupt driven
+    /// transfer state machine
+    fn polled_read(
+        &self,
+        ic_tar: &LocalRegisterCopy<u32, IC_TAR::Register>,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.check_resumed()?;
+        self.acquire_bus_semaphore()?;
+        let ret = self.polled_read_owned(ic_tar, buf);
+        self.release_bus_semaphore();
+        ret
+    }
+
+    fn polled_read_owned(
+        &self,
+        ic_tar: &LocalRegisterCopy<u32, IC_TAR::Register>,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.pm_get()?;
+        let ret = self.polled_read_powered(ic_tar, buf);
+        self.pm_put();
+        ret
+    }
+
+    fn polled_read_powered(
+        &self,
+        ic_tar: &LocalRegisterCopy<u32, IC_TAR::Register>,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        self.driver.wait_bus_not_busy()?;
+        self.in_flight.store(true, Ordering::Release);
+        self.disable(false);
+        self.driver.enable_10bitaddr(false);
+        self.driver.write_ic_tar(ic_tar);
+        self.driver.disable_all_interrupt();
+        self.driver.enable_controler();
+
+        let ret = self.driver.polled_read(buf);
+        self.disable(true);
+        self.in_flight.store(false, Ordering::Release);
+        self.idle();
+        ret
+    }
+
+    /// Enable reception of SMBus Host Notify, `handler` is called from the
+    /// irq handler with the notifying address and data word
+    ///
+    /// Host Notify is a write from the device to the SMBus host address,
+    /// which the adapter only sees while it is enabled, so the adapter is
+    /// kept enabled (with I2C interrupts masked) between transfers. That
+    /// takes the controller from firmware sharing it, so this fails with
+    /// `Errno::NotSupported` if a bus semaphore is set.
+    pub fn enable_host_notify(&self, handler: SmbusHostNotifyHandler) -> Result<()> {
+        if self.bus_semaphore.is_some() {
+            log_err!("Host Notify can not be used with a bus semaphore");
+            return to_error(Errno::NotSupported);
+        }
+
+        let mut smbus = self.smbus.lock();
+        smbus.host_notify_handler = Some(handler);
+        smbus.intr_mask.modify(IC_SMBUS_INTR::HOST_NOTIFY_MST_DET.val(1));
+        drop(smbus);
+        // runtime PM keeps the block on from now
+        if let Err(e) = self.update_smbus_interrupt_mask() {
+            log_err!("{:?} while turning on for Host Notify", e);
+            let mut smbus = self.smbus.lock();
+            smbus.intr_mask.modify(IC_SMBUS_INTR::HOST_NOTIFY_MST_DET.val(0));
+            smbus.host_notify_handler = None;
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Disable reception of SMBus Host Notify
+    pub fn disable_host_notify(&self) {
+        let mut smbus = self.smbus.lock();
+        smbus.intr_mask.modify(IC_SMBUS_INTR::HOST_NOTIFY_MST_DET.val(0));
+        smbus.host_notify_handler = None;
+        drop(smbus);
+        if let Err(e) = self.update_smbus_interrupt_mask() {
+            log_err!("{:?} while disabling Host Notify", e);
+        }
+    }
+
+    /// Assert (`true`) or deassert (`false`) SMBSUS# to put the SMBus
+    /// devices into or bring them out of their suspend state
+    ///
+    /// Runtime PM keeps the block on while SMBSUS# is asserted.
+    pub fn smbus_suspend(&self, assert: bool) -> Result<()> {
+        let _bus = self.lock_bus();
+        self.check_resumed()?;
+        self.acquire_bus_semaphore()?;
+        let ret = self.pm_get();
+        if ret.is_ok() {
+            log_info!("SMBus suspend {}", if assert { "assert" } else { "deassert" });
+            self.smbus_suspended.store(assert, Ordering::Release);
+            self.driver.enable_smbus_suspend(assert);
+            self.pm_put();
+        }
+        self.release_bus_semaphore();
+        ret
+    }
+
+    /// Return true if SMBSUS# is asserted on the bus
+    pub fn smbus_suspend_status(&self) -> Result<bool> {
+        let _bus = self.lock_bus();
+        self.check_resumed()?;
+        self.acquire_bus_semaphore()?;
+        let ret = self.pm_get().map(|_| {
+            let asserted = self.driver.ic_status().is_set(IC_STATUS::SMBUS_SUSPEND_STATUS);
+            self.pm_put();
+            asserted
+        });
+        self.release_bus_semaphore();
+        ret
+    }
+
+    /// Recover from a device stretching SCL beyond the SMBus limits
+    fn smbus_recover(&self) {
+        // nested in the semaphore of the transfer
+        if self.acquire_bus_semaphore().is_err() {
+            return;
+        }
+        log_warn!("SMBus timeout, resetting the bus");
+        // master_setup implicitly disables the adapter
+        self.master_setup();
+        self.driver.enable_controler();
+        let _ = self.driver.smbus_clk_reset();
+        self.disable(false);
+        self.release_bus_semaphore();
+    }
+
+    /// Put the adapter in its idle state after a transfer
+    fn idle(&self) {
+        // Keep listening for Host Notify. All I2C interrupts are
+        // masked and TX FIFO is empty, so the master stays quiet.
+        // Host Notify is refused with a bus semaphore, so the firmware
+        // never finds the adapter enabled behind its back.
+        if self.smbus.lock().host_notify_handler.is_some() {
+            self.driver.enable_controler();
+        }
+    }
+
+    fn host_notify_process(&self) {
+        // The RX FIFO belongs to the master read in flight, leave it
+        // alone, the notification is lost.
+        if self.in_flight.load(Ordering::Acquire) {
+            log_warn!("SMBus host notify during a transfer, dropped");
+            return;
+        }
+
+        let rx_valid = self.driver.ic_rxflr().get();
+        if rx_valid < SMBUS_HOST_NOTIFY_LEN {
+            log_warn!("short SMBus host notify, {} bytes in rx fifo", rx_valid);
+            for _ in 0..rx_valid {
+                let _ = self.driver.ic_data_cmd();
+            }
+            return;
+        }
+
+        let mut buf = [0u8; SMBUS_HOST_NOTIFY_LEN as usize];
+        for byte in buf.iter_mut() {
+            *byte = self.driver.ic_data_cmd().read(IC_DATA_CMD::DAT) as u8;
+        }
+        let addr = (buf[0] >> 1) as u16;
+        let data = u16::from_le_bytes([buf[1], buf[2]]);
+        log_debug!("SMBus host notify from 0x{:x}, data 0x{:x}", addr, data);
+
+        let handler = self.smbus.lock().host_notify_handler;
+        if let Some(handler) = handler {
+            handler(addr, data);
+        }
+    }
+
+    /// Handle SMBus interrupts, return true if any was pending
+    fn smbus_irq_process(&self) -> bool {
+        let smbus_intr_mask = self.smbus.lock().intr_mask;
+        if smbus_intr_mask.get() == 0 {
+            return false;
+        }
+
+        let stat = self.driver.read_and_clean_smbus_intrbits();
+        if stat.get() == 0 {
+            return false;
+        }
+
+        if stat.is_set(IC_SMBUS_INTR::SMBUS_ALERT_DET) {
+            // SMBALERT# stays asserted until the device is serviced through
+            // the ARA, keep it masked until smbus_alert_process is done.
+            let mut mask = smbus_intr_mask;
+            mask.modify(IC_SMBUS_INTR::SMBUS_ALERT_DET.val(0));
+            self.driver.write_smbus_interrupt_mask(&mask);
+            self.smbus_alert_pending.store(true, Ordering::Release);
+        }
+
+        if stat.is_set(IC_SMBUS_INTR::MST_CLOCK_EXTND_TIMEOUT)
+            || stat.is_set(IC_SMBUS_INTR::SLV_CLOCK_EXTND_TIMEOUT)
+        {
+            log_err!("SMBus clock low extension timeout, stat: {:x}", stat.get());
+            let mut transfer = self.xfer.lock();
+            if transfer.is_active() {
+                // Abort the transfer, masking the I2C interrupts also
+                // keeps irq_handler away from the state machine
+                transfer.cmd_err |= DwI2cCmdErr::CLK_EXTND_TIMEOUT;
+                transfer.status = DwI2cStatus::empty();
+                drop(transfer);
+                self.driver.disable_all_interrupt();
+                self.cmd_complete.complete();
+            }
+        }
+
+        if stat.is_set(IC_SMBUS_INTR::HOST_NOTIFY_MST_DET) {
+            self.host_notify_process();
+        }
+        true
+    }
+
     fn master_default_intr_mask() -> LocalRegisterCopy<u32, IC_INTR::Register> {
         let mut mask = LocalRegisterCopy::new(0);
         mask.modify(IC_INTR::RX_FULL.val(0b1));
@@ -678,3 +2151,113 @@ impl I2cDwMasterDriver {
         mask
     }
 }
+
+/// Gives up the borrow of the `ops` of `master_transfer_ops` when the
+/// transfer returns, on every path
+struct OpsBorrow<'a, A: I2cDwRegAccess> {
+    master: &'a I2cDwMasterDriver<A>,
+}
+
+impl<A: I2cDwRegAccess> Drop for OpsBorrow<'_, A> {
+    fn drop(&mut self) {
+        // the transfer is over and interrupt is disabled,
+        // so here lock is safety
+        self.master.xfer.lock().msgs = XferMsgs::default();
+    }
+}
+
+/// Exclusive ownership of an `I2cDwMasterDriver`, returned by `lock_bus`
+///
+/// The bus is released when the guard is dropped.
+pub struct I2cDwBusGuard<'a, A: I2cDwRegAccess = I2cDwMmio> {
+    master: &'a I2cDwMasterDriver<A>,
+}
+
+impl<A: I2cDwRegAccess> I2cDwBusGuard<'_, A> {
+    /// Prepare controller for a transaction and call xfer_msg
+    ///
+    /// After lost arbitration the whole msg list is re-run as set by
+    /// `I2cDwMasterDriver::set_retry_policy`.
+    pub fn master_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        self.master.xfer_msgs(msgs, TarMode::Normal)
+    }
+
+    /// Return the number of retries the last transfer through this
+    /// guard used
+    pub fn retries_used(&self) -> u32 {
+        self.master.retries_used.load(Ordering::Relaxed)
+    }
+
+    /// Like `master_transfer`, but hand `msgs` back with the read buffers
+    /// filled, and store the number of bytes each msg transferred in
+    /// `xfer_len`, which also tells the partial progress on error
+    ///
+    /// `xfer_len` needs an entry per msg, else `Errno::InvalidArgs` is
+    /// returned. Written bytes flushed from the TX FIFO by an abort are
+    /// not counted.
+    pub fn master_transfer_msgs(
+        &self,
+        msgs: Vec<I2cMsg>,
+        xfer_len: &mut [usize],
+    ) -> (Vec<I2cMsg>, Result<i32>) {
+        if xfer_len.len() < msgs.len() {
+            log_err!("can not track the length of {} msgs", msgs.len());
+            return (msgs, to_error(Errno::InvalidArgs));
+        }
+
+        let ret = self.master.xfer_msgs(msgs, TarMode::Normal);
+
+        // the transfer is over and interrupt is disabled,
+        // so here lock is safety
+        let mut transfer = self.master.xfer.lock();
+        let msgs = transfer.msgs.take_msgs();
+        for (i, len) in xfer_len[..msgs.len()].iter_mut().enumerate() {
+            *len = transfer.xfer_len.get(i).copied().unwrap_or(0);
+        }
+        (msgs, ret)
+    }
+
+    /// Transfer `ops` to `addr`, a 10-bit address if `ten_bit`, into and
+    /// out of borrowed buffers
+    ///
+    /// The state machine only keeps a reference to `ops` and indices into
+    /// it, so no allocator is needed. Empty `ops`, empty buffers or an
+    /// address out of range are rejected with `Errno::InvalidArgs`.
+    ///
+    /// After lost arbitration (`ARB_LOST` or `ABRT_SLV_ARBLOST`) the
+    /// whole `ops` list is re-run as set by `set_retry_policy`, with no
+    /// copy as the `ops` can be rewound.
+    pub fn master_transfer_ops(
+        &self,
+        addr: u16,
+        ten_bit: bool,
+        ops: &mut [Operation<'_>],
+    ) -> Result<i32> {
+        self.master.xfer_ops(addr, ten_bit, ops)
+    }
+
+    /// Send `msgs` to the General Call address 0x00, e.g. a single 0x06
+    /// byte for a software reset of all devices supporting it
+    ///
+    /// The address of `msgs` is ignored. General Call is write only,
+    /// read msgs are rejected with `Errno::InvalidArgs`.
+    pub fn general_call_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        if msgs.iter().any(|msg| msg.flags().contains(I2cMsgFlags::I2cMasterRead)) {
+            log_err!("General Call can not read");
+            return to_error(Errno::InvalidArgs);
+        }
+        self.master.xfer_msgs(msgs, TarMode::GeneralCall)
+    }
+
+    /// Transfer `msgs` prefixed by a START byte (0x01), which gives slow
+    /// targets polling SDA time to detect the START condition
+    pub fn start_byte_transfer(&self, msgs: Vec<I2cMsg>) -> Result<i32> {
+        self.master.xfer_msgs(msgs, TarMode::StartByte)
+    }
+}
+
+impl<A: I2cDwRegAccess> Drop for I2cDwBusGuard<'_, A> {
+    fn drop(&mut self) {
+        self.master.bus_lock.unlock();
+    }
+}
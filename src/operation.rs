@@ -0,0 +1,495 @@
+use core::ptr::NonNull;
+
+use osl::{
+    vec::Vec,
+    error::{Result, to_error, Errno},
+    driver::i2c::{I2cMsg, I2cMsgFlags, GeneralI2cMsg},
+};
+
+/// A borrowed-buffer transfer operation, modeled after embedded-hal
+/// `Operation`
+///
+/// All operations of a transfer go to the same target, with a repeated
+/// START between them and a STOP after the last one. Nothing is allocated
+/// on this path.
+pub struct Operation<'a> {
+    buf: OperationBuf<'a>,
+    /// bytes written, or read commands queued
+    cmd_cnt: usize,
+    /// bytes read
+    recv_cnt: usize,
+}
+
+enum OperationBuf<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl<'a> Operation<'a> {
+    /// Read `buf.len()` bytes into `buf`
+    pub fn read(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf: OperationBuf::Read(buf),
+            cmd_cnt: 0,
+            recv_cnt: 0,
+        }
+    }
+
+    /// Write all bytes of `buf`
+    pub fn write(buf: &'a [u8]) -> Self {
+        Self {
+            buf: OperationBuf::Write(buf),
+            cmd_cnt: 0,
+            recv_cnt: 0,
+        }
+    }
+
+    /// Length of the buffer
+    pub fn len(&self) -> usize {
+        match &self.buf {
+            OperationBuf::Read(buf) => buf.len(),
+            OperationBuf::Write(buf) => buf.len(),
+        }
+    }
+
+    /// Whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        self.cmd_cnt = 0;
+        self.recv_cnt = 0;
+    }
+}
+
+/// An `I2cMsg` copied into an owned buffer before the transfer, so the
+/// transfer can be re-run after lost arbitration
+pub(crate) struct StagedMsg {
+    addr: u16,
+    /// flags of the msg as passed in
+    flags: I2cMsgFlags,
+    /// bytes to write, or room for the bytes read
+    buf: Vec<u8>,
+    /// length of `buf` as passed in, a RECV_LEN read grows it
+    len: usize,
+    /// bytes written, or read commands queued
+    cmd_cnt: usize,
+    /// bytes read
+    recv_cnt: usize,
+    /// block length and read commands set on the RECV_LEN byte
+    recv_len: Option<(usize, isize)>,
+    /// All of the msg is in `buf`, else the bytes after it are still in
+    /// the msg, only for a write whose staging ran out of memory
+    drained: bool,
+}
+
+impl StagedMsg {
+    fn new(msg: &I2cMsg) -> Self {
+        Self {
+            addr: msg.addr(),
+            flags: msg.flags(),
+            buf: Vec::new(),
+            len: 0,
+            cmd_cnt: 0,
+            recv_cnt: 0,
+            recv_len: None,
+            drained: false,
+        }
+    }
+
+    /// Drain `msg` into the owned buffer, with room for `recv_len_max`
+    /// bytes if it is a RECV_LEN read
+    ///
+    /// On `Errno::NoMemory` the bytes drained so far stay in the buffer
+    /// and the rest in `msg`.
+    fn drain(&mut self, msg: &mut I2cMsg, recv_len_max: usize) -> Result<()> {
+        let read = self.flags.contains(I2cMsgFlags::I2cMasterRead);
+        if self.flags.contains(I2cMsgFlags::I2cMasterRecvLen) &&
+            self.buf.try_reserve_exact(recv_len_max).is_err()
+        {
+            return to_error(Errno::NoMemory);
+        }
+        // the msg ends up as if all of it was queued
+        while !msg.send_end() {
+            // room first, so no byte is lost
+            reserve_one(&mut self.buf)?;
+            let byte = if read {
+                msg.inc_recieve_cmd_cnt();
+                0
+            } else {
+                msg.pop_front_byte()
+            };
+            push_reserved(&mut self.buf, byte);
+            self.len = self.buf.len();
+        }
+        self.drained = true;
+        Ok(())
+    }
+
+    fn flags(&self) -> I2cMsgFlags {
+        match self.recv_len {
+            Some(_) => self.flags - I2cMsgFlags::I2cMasterRecvLen,
+            None => self.flags,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.buf.truncate(self.len);
+        self.cmd_cnt = 0;
+        self.recv_cnt = 0;
+        self.recv_len = None;
+    }
+
+    /// Hand the bytes read over to `msg`, as the state machine does
+    fn finish(&self, msg: &mut I2cMsg) {
+        if !self.flags.contains(I2cMsgFlags::I2cMasterRead) {
+            return;
+        }
+        if let Some((len, cmd_cnt)) = self.recv_len {
+            msg.modify_recieve_threshold(len);
+            msg.modify_recieve_cmd_cnt(cmd_cnt);
+            msg.remove_flag(I2cMsgFlags::I2cMasterRecvLen);
+        }
+        for &byte in &self.buf[..self.recv_cnt] {
+            msg.push_byte(byte);
+        }
+    }
+}
+
+/// Append `item` to `buf`, failing with `Errno::NoMemory`
+///
+/// Only fallible allocation is available in the Linux kernel, so the
+/// item is written through `try_reserve` rather than `push`.
+fn try_push<T>(buf: &mut Vec<T>, item: T) -> Result<()> {
+    reserve_one(buf)?;
+    push_reserved(buf, item);
+    Ok(())
+}
+
+/// Make room for one more item in `buf`, failing with `Errno::NoMemory`
+fn reserve_one<T>(buf: &mut Vec<T>) -> Result<()> {
+    if buf.len() == buf.capacity() && buf.try_reserve(1).is_err() {
+        return to_error(Errno::NoMemory);
+    }
+    Ok(())
+}
+
+/// Append `item` to `buf`, which has room for it
+fn push_reserved<T>(buf: &mut Vec<T>, item: T) {
+    buf.spare_capacity_mut()[0].write(item);
+    // SAFETY: the item past the length was just initialized
+    unsafe { buf.set_len(buf.len() + 1) };
+}
+
+/// The msgs of the transfer in progress
+pub(crate) enum XferMsgs {
+    /// Owned `I2cMsg`s
+    Msgs(Vec<I2cMsg>),
+    /// Owned `I2cMsg`s copied into `staged`, which the state machine uses
+    /// instead of the first `staged.len()` msgs
+    Staged {
+        msgs: Vec<I2cMsg>,
+        staged: Vec<StagedMsg>,
+        /// All msgs are drained into `staged`, so they can be re-run
+        rewindable: bool,
+    },
+    /// Borrowed operations, only valid while the transfer is in progress
+    Ops {
+        ops: OpsPtr,
+        len: usize,
+        addr: u16,
+        ten_bit: bool,
+    },
+}
+
+/// The borrowed operations of `XferMsgs::Ops`, lifetime erased
+pub(crate) struct OpsPtr(NonNull<Operation<'static>>);
+
+// SAFETY: only built from a `&mut [Operation]` held by the transferring
+// thread until the transfer is over and the msgs are reset
+unsafe impl Send for OpsPtr {}
+
+impl Default for XferMsgs {
+    fn default() -> Self {
+        Self::Msgs(Vec::new())
+    }
+}
+
+impl XferMsgs {
+    /// Borrow `ops` to `addr`, a 10-bit address if `ten_bit`, the caller
+    /// resets the msgs before the borrow ends
+    pub(crate) fn from_ops(addr: u16, ten_bit: bool, ops: &mut [Operation<'_>]) -> Self {
+        for op in ops.iter_mut() {
+            op.reset();
+        }
+        Self::Ops {
+            len: ops.len(),
+            // the lifetime is erased, see above
+            ops: OpsPtr(NonNull::from(ops).cast::<Operation<'static>>()),
+            addr,
+            ten_bit,
+        }
+    }
+
+    /// Whether these are the borrowed `Ops`, which must not allocate
+    #[inline]
+    pub(crate) fn is_borrowed(&self) -> bool {
+        matches!(self, Self::Ops { .. })
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Msgs(msgs) => msgs.len(),
+            Self::Staged { msgs, .. } => msgs.len(),
+            Self::Ops { len, .. } => *len,
+        }
+    }
+
+    /// Copy owned msgs into buffers of the driver, so `rewind` can re-run
+    /// them, RECV_LEN reads get room for `recv_len_max` bytes
+    ///
+    /// If this fails with `Errno::NoMemory` the msgs can still be
+    /// transferred once, but not re-run: the msgs staged so far stay
+    /// staged, a write partly staged continues from its msg, and the rest
+    /// of the msgs are used as they are.
+    pub(crate) fn stage(&mut self, recv_len_max: usize) -> Result<()> {
+        let Self::Msgs(msgs) = self else {
+            return Ok(());
+        };
+
+        let mut staged = Vec::new();
+        if staged.try_reserve_exact(msgs.len()).is_err() {
+            return to_error(Errno::NoMemory);
+        }
+        let mut ret = Ok(());
+        for msg in msgs.iter_mut() {
+            let mut staged_msg = StagedMsg::new(msg);
+            ret = staged_msg.drain(msg, recv_len_max);
+            if ret.is_err() {
+                if staged_msg.flags.contains(I2cMsgFlags::I2cMasterRead) {
+                    // nothing was read yet, hand the whole read back
+                    msg.modify_recieve_cmd_cnt(0);
+                } else if !staged_msg.buf.is_empty() {
+                    push_reserved(&mut staged, staged_msg);
+                }
+                break;
+            }
+            push_reserved(&mut staged, staged_msg);
+        }
+        let msgs = core::mem::take(msgs);
+        *self = Self::Staged { msgs, staged, rewindable: ret.is_ok() };
+        ret
+    }
+
+    /// Start the msgs over, false if they were not staged
+    pub(crate) fn rewind(&mut self) -> bool {
+        match self {
+            Self::Msgs(_) => false,
+            Self::Staged { staged, rewindable, .. } => {
+                if !*rewindable {
+                    return false;
+                }
+                staged.iter_mut().for_each(StagedMsg::reset);
+                true
+            }
+            Self::Ops { ops, len, .. } => {
+                // SAFETY: valid while the transfer is in progress
+                let ops = unsafe { core::slice::from_raw_parts_mut(ops.0.as_ptr(), *len) };
+                ops.iter_mut().for_each(Operation::reset);
+                true
+            }
+        }
+    }
+
+    /// Take the owned msgs back
+    pub(crate) fn take_msgs(&mut self) -> Vec<I2cMsg> {
+        match core::mem::take(self) {
+            Self::Msgs(msgs) => msgs,
+            Self::Staged { mut msgs, staged, .. } => {
+                for (msg, staged) in msgs.iter_mut().zip(&staged) {
+                    staged.finish(msg);
+                }
+                msgs
+            }
+            Self::Ops { .. } => Vec::new(),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, idx: usize) -> XferMsgRef<'_> {
+        match self {
+            Self::Msgs(msgs) => XferMsgRef::Msg(&mut msgs[idx]),
+            Self::Staged { msgs, staged, .. } => match staged.get_mut(idx) {
+                Some(staged) => XferMsgRef::Staged(staged, &mut msgs[idx]),
+                None => XferMsgRef::Msg(&mut msgs[idx]),
+            },
+            Self::Ops { ops, len, addr, ten_bit } => {
+                assert!(idx < *len);
+                // SAFETY: in bounds, and valid while the transfer is in progress
+                let op = unsafe { &mut *ops.0.as_ptr().add(idx) };
+                XferMsgRef::Op { op, addr: *addr, ten_bit: *ten_bit }
+            }
+        }
+    }
+}
+
+/// A msg of the transfer in progress, as seen by the state machine
+pub(crate) enum XferMsgRef<'a> {
+    Msg(&'a mut I2cMsg),
+    /// A staged msg, and its `I2cMsg` holding what was not drained
+    Staged(&'a mut StagedMsg, &'a mut I2cMsg),
+    Op { op: &'a mut Operation<'static>, addr: u16, ten_bit: bool },
+}
+
+impl XferMsgRef<'_> {
+    pub(crate) fn addr(&self) -> u16 {
+        match self {
+            Self::Msg(msg) => msg.addr(),
+            Self::Staged(msg, _) => msg.addr,
+            Self::Op { addr, .. } => *addr,
+        }
+    }
+
+    pub(crate) fn flags(&self) -> I2cMsgFlags {
+        match self {
+            Self::Msg(msg) => msg.flags(),
+            Self::Staged(msg, _) => msg.flags(),
+            Self::Op { op, ten_bit, .. } => {
+                let mut flags = I2cMsgFlags::empty();
+                if let OperationBuf::Read(_) = op.buf {
+                    flags.insert(I2cMsgFlags::I2cMasterRead);
+                }
+                if *ten_bit {
+                    flags.insert(I2cMsgFlags::I2cAddrTen);
+                }
+                flags
+            }
+        }
+    }
+
+    /// All bytes written, or all read commands queued
+    pub(crate) fn send_end(&self) -> bool {
+        match self {
+            Self::Msg(msg) => msg.send_end(),
+            Self::Staged(msg, rest) => {
+                msg.cmd_cnt >= msg.buf.len() && (msg.drained || rest.send_end())
+            }
+            Self::Op { op, .. } => op.cmd_cnt >= op.len(),
+        }
+    }
+
+    /// One byte or read command left to queue
+    pub(crate) fn send_left_last(&self) -> bool {
+        match self {
+            Self::Msg(msg) => msg.send_left_last(),
+            Self::Staged(msg, rest) => {
+                if msg.cmd_cnt < msg.buf.len() {
+                    msg.cmd_cnt + 1 == msg.buf.len() && (msg.drained || rest.send_end())
+                } else {
+                    !msg.drained && rest.send_left_last()
+                }
+            }
+            Self::Op { op, .. } => op.cmd_cnt + 1 == op.len(),
+        }
+    }
+
+    pub(crate) fn pop_front_byte(&mut self) -> u8 {
+        match self {
+            Self::Msg(msg) => msg.pop_front_byte(),
+            Self::Staged(msg, rest) => {
+                // read msgs were staged as zeroes
+                if msg.cmd_cnt >= msg.buf.len() {
+                    return rest.pop_front_byte();
+                }
+                let byte = msg.buf[msg.cmd_cnt];
+                msg.cmd_cnt += 1;
+                byte
+            }
+            Self::Op { op, .. } => {
+                let byte = match &op.buf {
+                    OperationBuf::Write(buf) => buf[op.cmd_cnt],
+                    OperationBuf::Read(_) => 0,
+                };
+                op.cmd_cnt += 1;
+                byte
+            }
+        }
+    }
+
+    pub(crate) fn inc_recieve_cmd_cnt(&mut self) {
+        match self {
+            Self::Msg(msg) => msg.inc_recieve_cmd_cnt(),
+            Self::Staged(msg, _) => msg.cmd_cnt += 1,
+            Self::Op { op, .. } => op.cmd_cnt += 1,
+        }
+    }
+
+    pub(crate) fn recieve_end(&self) -> bool {
+        match self {
+            Self::Msg(msg) => msg.recieve_end(),
+            Self::Staged(msg, _) => msg.recv_cnt >= msg.buf.len(),
+            Self::Op { op, .. } => op.recv_cnt >= op.len(),
+        }
+    }
+
+    pub(crate) fn push_byte(&mut self, byte: u8) {
+        match self {
+            Self::Msg(msg) => msg.push_byte(byte),
+            Self::Staged(msg, _) => {
+                msg.buf[msg.recv_cnt] = byte;
+                msg.recv_cnt += 1;
+            }
+            Self::Op { op, .. } => {
+                if let OperationBuf::Read(buf) = &mut op.buf {
+                    buf[op.recv_cnt] = byte;
+                }
+                op.recv_cnt += 1;
+            }
+        }
+    }
+
+    // Operations never carry I2cMasterRecvLen, so the block length
+    // adjustments below only apply to msgs
+
+    pub(crate) fn modify_recieve_threshold(&mut self, len: usize) {
+        match self {
+            Self::Msg(msg) => msg.modify_recieve_threshold(len),
+            Self::Staged(msg, _) => {
+                // within the room reserved by `stage`, no allocation here
+                let len = len.min(msg.buf.capacity());
+                while msg.buf.len() < len {
+                    let _ = try_push(&mut msg.buf, 0);
+                }
+                msg.buf.truncate(len);
+                msg.recv_len = Some((len, 0));
+            }
+            Self::Op { .. } => (),
+        }
+    }
+
+    pub(crate) fn modify_recieve_cmd_cnt(&mut self, cnt: isize) {
+        match self {
+            Self::Msg(msg) => msg.modify_recieve_cmd_cnt(cnt),
+            Self::Staged(msg, _) => {
+                // the first `cnt` read commands are queued already
+                msg.cmd_cnt = cnt.max(0) as usize;
+                if let Some((_, cmd_cnt)) = &mut msg.recv_len {
+                    *cmd_cnt = cnt;
+                }
+            }
+            Self::Op { .. } => (),
+        }
+    }
+
+    pub(crate) fn remove_flag(&mut self, flag: I2cMsgFlags) {
+        match self {
+            Self::Msg(msg) => msg.remove_flag(flag),
+            // RECV_LEN is dropped once `recv_len` is set, and restored by
+            // `reset`
+            Self::Staged(..) => (),
+            Self::Op { .. } => (),
+        }
+    }
+}
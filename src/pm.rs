@@ -0,0 +1,20 @@
+use osl::error::Result;
+
+/// Clock and reset provider of the platform, used for runtime power
+/// management of `I2cDwMasterDriver`
+///
+/// The driver turns the block on before a transfer and off once it
+/// stayed idle for the autosuspend delay, see
+/// `I2cDwMasterDriver::set_platform_hooks`.
+pub trait I2cDwPlatformHooks: Sync {
+    /// Enable the APB and IC clocks
+    fn clk_enable(&self) -> Result<()>;
+    /// Disable the APB and IC clocks
+    fn clk_disable(&self);
+    /// Hold the block in reset
+    fn reset_assert(&self);
+    /// Release the block from reset
+    fn reset_deassert(&self) -> Result<()>;
+    /// Current IC clock rate in kHz, 0 if unknown
+    fn clk_rate_khz(&self) -> u32;
+}
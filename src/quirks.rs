@@ -0,0 +1,48 @@
+use osl::driver::i2c::{I2cMsgFlags, I2C_SMBUS_BLOCK_MAX};
+
+/// Max number of msgs of a transfer, the limit Linux puts on an
+/// `I2C_RDWR` transfer (`I2C_RDWR_IOCTL_MAX_MSGS`)
+pub const I2C_DW_XFER_MAX_MSGS: usize = 42;
+
+/// What the adapter can not do, modeled after Linux `i2c_adapter_quirks`
+///
+/// `master_transfer` rejects msgs breaking any of them with
+/// `Errno::NotSupported`, before the bus is touched.
+#[derive(Debug, Clone, Copy)]
+pub struct I2cDwAdapterQuirks {
+    /// Msg flags the DW core can not implement
+    pub unsupported_flags: I2cMsgFlags,
+    /// Max number of msgs of a transfer, the bytes transferred are
+    /// tracked per msg
+    pub max_num_msgs: usize,
+    /// `I2cMasterNoStart` only continues a write after a write without
+    /// `I2cMasterStop`
+    pub nostart_write_only: bool,
+    /// `I2cMasterIgnoreNak` is only honoured on a write as last msg, for
+    /// a NAK of its last byte. The DW core aborts the transfer on any
+    /// other NAK.
+    pub ignore_nak_last_only: bool,
+    /// All msgs of a transfer go to one target address, IC_TAR is only
+    /// written before the transfer starts
+    pub single_addr: bool,
+    /// No zero-length msgs, the DW core can not send an address without
+    /// a data or read command
+    pub no_zero_len: bool,
+    /// Max block length of an `I2cMasterRecvLen` read, a larger length
+    /// byte ends the read after it
+    pub max_recv_len: u8,
+    /// No write after an `I2cMasterRecvLen` read, TX_EMPTY is masked
+    /// until the length byte is in
+    pub no_write_after_recv_len: bool,
+}
+
+pub(crate) const DW_I2C_QUIRKS: I2cDwAdapterQuirks = I2cDwAdapterQuirks {
+    unsupported_flags: I2cMsgFlags::I2cMasterNoReadAck.union(I2cMsgFlags::I2cMasterRevDirAddr),
+    max_num_msgs: I2C_DW_XFER_MAX_MSGS,
+    nostart_write_only: true,
+    ignore_nak_last_only: true,
+    single_addr: true,
+    no_zero_len: true,
+    max_recv_len: I2C_SMBUS_BLOCK_MAX,
+    no_write_after_recv_len: true,
+};
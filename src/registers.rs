@@ -1,108 +1,295 @@
 //! The official documentation: <https://www.synopsys.com/dw/ipdir.php?c=DW_apb_i2c>
 
-use core::ops::Deref;
+use core::marker::PhantomData;
 use core::ptr::NonNull;
 
+use osl::error::{to_error, Errno, Result};
+
+use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_bitfields;
-use tock_registers::registers::{ReadOnly, ReadWrite, WriteOnly};
+use tock_registers::RegisterLongName;
+
+/// How the registers are accessed, probed from IC_COMP_TYPE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DwRegAccess {
+    /// 32-bit accesses
+    Native32,
+    /// Two 16-bit accesses, low half first, for a 16-bit APB data width
+    Split16,
+    /// 32-bit accesses, byte swapped, for a big-endian bus
+    Swab32,
+}
 
-/// DwApbI2cRegisters pointer wrapper
-pub(crate) struct DwApbI2cRegistersRef {
-    ptr: NonNull<DwApbI2cRegisters>,
+/// Backend the registers are read and written through, e.g. MMIO, a
+/// PCIe BAR at an offset or a register bridge over SPI
+///
+/// `offset` is the byte offset of the register in the DW APB I2C
+/// register map.
+pub trait I2cDwRegAccess: Send + Sync {
+    /// Read the 32-bit register at `offset`
+    fn read32(&self, offset: usize) -> u32;
+    /// Write the 32-bit register at `offset`
+    fn write32(&self, offset: usize, val: u32);
+
+    /// Read the 16-bit half register at `offset`, only used when the
+    /// IC_COMP_TYPE probe finds a 16-bit APB data width
+    ///
+    /// This must be a real 16-bit access, a read of the whole word could
+    /// pop IC_DATA_CMD or clear interrupts by reading their neighbour.
+    fn read16(&self, offset: usize) -> u16;
+    /// Write the 16-bit half register at `offset`, see `read16`
+    fn write16(&self, offset: usize, val: u16);
 }
 
-impl DwApbI2cRegistersRef {
-    /// Create a new `StaticRef` from a raw pointer
+/// Direct volatile MMIO, the default register backend
+pub struct I2cDwMmio {
+    ptr: NonNull<u8>,
+}
+
+// SAFETY: the registers are accessed with volatile reads and writes only,
+// the driver serializes what needs to be
+unsafe impl Send for I2cDwMmio {}
+unsafe impl Sync for I2cDwMmio {}
+
+impl I2cDwMmio {
+    /// Create a new `I2cDwMmio` from the register base
     ///
     /// ## Safety
     ///
-    /// - `ptr` must be aligned, non-null, and dereferencable as `T`.
+    /// - `ptr` must be aligned, non-null, and dereferencable as the
+    ///   register map.
     /// - `*ptr` must be valid for the program duration.
-    pub(crate) const fn new(ptr: *mut u8) -> DwApbI2cRegistersRef {
+    pub const unsafe fn new(ptr: *mut u8) -> I2cDwMmio {
+        I2cDwMmio {
+            // SAFETY: non-null as promised by the caller
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+        }
+    }
+
+    /// Create a new `I2cDwMmio` from the register base, failing with
+    /// `Errno::InvalidArgs` if it is null or not 32-bit aligned
+    ///
+    /// Whether `ptr` really maps a DW APB I2C block is only told by
+    /// reading it, see `probe` of the drivers.
+    ///
+    /// ## Safety
+    ///
+    /// - `ptr`, if non-null and aligned, must be dereferencable as the
+    ///   register map.
+    /// - `*ptr` must be valid for the program duration.
+    pub unsafe fn try_new(ptr: *mut u8) -> Result<I2cDwMmio> {
+        if ptr.is_null() {
+            log_err!("register base is null");
+            return to_error(Errno::InvalidArgs);
+        }
+        if ptr as usize & 0x3 != 0 {
+            log_err!("register base {:p} is not 32-bit aligned", ptr);
+            return to_error(Errno::InvalidArgs);
+        }
+        // SAFETY: checked above, the mapping is promised by the caller
+        Ok(unsafe { Self::new(ptr) })
+    }
+}
+
+impl I2cDwRegAccess for I2cDwMmio {
+    #[inline]
+    fn read32(&self, offset: usize) -> u32 {
+        // SAFETY: `ptr` is aligned and dereferencable for the program
+        // duration as promised by the caller of `new`, and `offset` is
+        // one of the register offsets below.
+        unsafe { (self.ptr.as_ptr().add(offset) as *const u32).read_volatile() }
+    }
+
+    #[inline]
+    fn write32(&self, offset: usize, val: u32) {
+        // SAFETY: see `read32`
+        unsafe { (self.ptr.as_ptr().add(offset) as *mut u32).write_volatile(val) }
+    }
+
+    #[inline]
+    fn read16(&self, offset: usize) -> u16 {
+        // SAFETY: see `read32`
+        unsafe { (self.ptr.as_ptr().add(offset) as *const u16).read_volatile() }
+    }
+
+    #[inline]
+    fn write16(&self, offset: usize, val: u16) {
+        // SAFETY: see `read32`
+        unsafe { (self.ptr.as_ptr().add(offset) as *mut u16).write_volatile(val) }
+    }
+}
+
+/// DwApbI2cRegisters backend wrapper
+pub(crate) struct DwApbI2cRegistersRef<A: I2cDwRegAccess> {
+    backend: A,
+    access: DwRegAccess,
+}
+
+impl<A: I2cDwRegAccess> DwApbI2cRegistersRef<A> {
+    pub(crate) const fn new(backend: A) -> DwApbI2cRegistersRef<A> {
         DwApbI2cRegistersRef {
-            ptr: NonNull::new(ptr).expect("ptr os null").cast(),
+            backend,
+            access: DwRegAccess::Native32,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set_access(&mut self, access: DwRegAccess) {
+        self.access = access;
+    }
+
+    /// Read the 32-bit register at `offset` as set by `access`
+    pub(crate) fn read(&self, offset: usize) -> u32 {
+        match self.access {
+            DwRegAccess::Native32 => self.backend.read32(offset),
+            DwRegAccess::Split16 => {
+                let low = self.backend.read16(offset) as u32;
+                let high = self.backend.read16(offset + 2) as u32;
+                low | (high << 16)
+            }
+            DwRegAccess::Swab32 => self.backend.read32(offset).swap_bytes(),
+        }
+    }
+
+    /// Write the 32-bit register at `offset` as set by `access`
+    pub(crate) fn write(&self, offset: usize, val: u32) {
+        match self.access {
+            DwRegAccess::Native32 => self.backend.write32(offset, val),
+            DwRegAccess::Split16 => {
+                self.backend.write16(offset, val as u16);
+                self.backend.write16(offset + 2, (val >> 16) as u16);
+            }
+            DwRegAccess::Swab32 => self.backend.write32(offset, val.swap_bytes()),
         }
     }
 }
 
-impl Deref for DwApbI2cRegistersRef {
-    type Target = DwApbI2cRegisters;
+/// Read-only register
+pub(crate) struct Ro;
+/// Read-write register
+pub(crate) struct Rw;
+/// Write-only register
+pub(crate) struct Wo;
+
+pub(crate) trait RegReadable {}
+impl RegReadable for Ro {}
+impl RegReadable for Rw {}
+
+pub(crate) trait RegWriteable {}
+impl RegWriteable for Wo {}
+impl RegWriteable for Rw {}
+
+/// A register of `DwApbI2cRegistersRef`, read and written through its
+/// access mode with the `tock_registers` interfaces
+pub(crate) struct DwReg<'a, R: RegisterLongName, M, A: I2cDwRegAccess> {
+    regs: &'a DwApbI2cRegistersRef<A>,
+    offset: usize,
+    _reg: PhantomData<(R, M)>,
+}
+
+impl<R: RegisterLongName, M: RegReadable, A: I2cDwRegAccess> Readable for DwReg<'_, R, M, A> {
+    type T = u32;
+    type R = R;
 
-    fn deref(&self) -> &DwApbI2cRegisters {
-        // SAFETY: `ptr` is aligned and dereferencable for the program
-        // duration as promised by the caller of `StaticRef::new`.
-        unsafe { self.ptr.as_ref() }
+    #[inline]
+    fn get(&self) -> u32 {
+        self.regs.read(self.offset)
+    }
+}
+
+impl<R: RegisterLongName, M: RegWriteable, A: I2cDwRegAccess> Writeable for DwReg<'_, R, M, A> {
+    type T = u32;
+    type R = R;
+
+    #[inline]
+    fn set(&self, value: u32) {
+        self.regs.write(self.offset, value)
     }
 }
 
-#[repr(C)]
-#[allow(non_snake_case)]
-pub(crate) struct DwApbI2cRegisters {
-    /// Distributor Control Register.
-    pub(crate) IC_CON: ReadWrite<u32, IC_CON::Register>,
-    pub(crate) IC_TAR: ReadWrite<u32, IC_TAR::Register>,
-    pub(crate) IC_SAR: ReadWrite<u32, IC_SAR::Register>,
-    pub(crate) IC_HS_MADDR: ReadWrite<u32, IC_HS_MADDR::Register>,
-    pub(crate) IC_DATA_CMD: ReadWrite<u32, IC_DATA_CMD::Register>,
-    pub(crate) IC_SS_OR_UFM_SCL_HCNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_SS_OR_UFM_SCL_LCNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_FS_SCL_HCNT_OR_UFM_TBUF_CNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_FS_SCL_LCNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_HS_SCL_HCNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_HS_SCL_LCNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_INTR_STAT: ReadOnly<u32, IC_INTR::Register>,
-    pub(crate) IC_INTR_MASK: ReadWrite<u32, IC_INTR::Register>,
-    pub(crate) IC_RAW_INTR_STAT: ReadOnly<u32, IC_INTR::Register>,
-    pub(crate) IC_RX_TL: ReadWrite<u32, IC_RX_TL::Register>,
-    pub(crate) IC_TX_TL: ReadWrite<u32, IC_TX_TL::Register>,
-    pub(crate) IC_CLR_INTR: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_RX_UNDER: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_RX_OVER: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_TX_OVER: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_RD_REQ: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_TX_ABRT: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_RX_DONE: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_ACTIVITY: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_STOP_DET: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_START_DET: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_CLR_GEN_CALL: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_ENABLE: ReadWrite<u32, IC_ENABLE::Register>,
-    pub(crate) IC_STATUS: ReadOnly<u32, IC_STATUS::Register>,
-    pub(crate) IC_TXFLR: ReadOnly<u32, IC_GENERAL_FLR::Register>,
-    pub(crate) IC_RXFLR: ReadOnly<u32, IC_GENERAL_FLR::Register>,
-    pub(crate) IC_SDA_HOLD: ReadWrite<u32, IC_SDA_HOLD::Register>,
-    pub(crate) IC_TX_ABRT_SOURCE: ReadOnly<u32, IC_TX_ABRT_SOURCE::Register>,
-    pub(crate) IC_SLV_DATA_NACK_ONLY: ReadWrite<u32, IC_SLV_DATA_NACK_ONLY::Register>,
-    pub(crate) IC_DMA_CR: ReadWrite<u32, IC_DMA_CR::Register>,
-    pub(crate) IC_DMA_TDLR: ReadWrite<u32, IC_GENERAL_FLR::Register>,
-    pub(crate) IC_DMA_RDLR: ReadWrite<u32, IC_GENERAL_FLR::Register>,
-    pub(crate) IC_SDA_SETUP: ReadWrite<u32, IC_SDA_SETUP::Register>,
-    pub(crate) IC_ACK_GENERAL_CALL: ReadWrite<u32, IC_ACK_GENERAL_CALL::Register>,
-    pub(crate) IC_ENABLE_STATUS: ReadOnly<u32, IC_ENABLE_STATUS::Register>,
-
-    pub(crate) IC_FS_OR_UFM_SPKLEN: ReadWrite<u32, IC_GENERAL_SPKLEN::Register>,
-    pub(crate) IC_HS_SPKLEN: ReadWrite<u32, IC_GENERAL_SPKLEN::Register>,
-    pub(crate) IC_CLR_RESTART_DET: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-
-    pub(crate) IC_SCL_STUCK_AT_LOW_TIMEOUT: ReadWrite<u32, IC_GENERAL_TIMEOUT::Register>,
-    pub(crate) IC_SDA_STUCK_AT_LOW_TIMEOUT: ReadWrite<u32, IC_GENERAL_TIMEOUT::Register>,
-    pub(crate) IC_CLR_SCL_STUCK_DET: ReadOnly<u32, IC_GENERAL_CLR::Register>,
-    pub(crate) IC_DEVICE_ID: ReadOnly<u32, IC_DEVICE_ID::Register>,
-
-    pub(crate) IC_SMBUS_CLOCK_LOW_SEXT: ReadWrite<u32, IC_GENERAL_TIMEOUT::Register>,
-    pub(crate) IC_SMBUS_CLOCK_LOW_MEXT: ReadWrite<u32, IC_GENERAL_TIMEOUT::Register>,
-    pub(crate) IC_SMBUS_THIGH_MAX_IDLE_COUNT: ReadWrite<u32, IC_GENERAL_CNT::Register>,
-    pub(crate) IC_SMBUS_INTR_STAT: ReadOnly<u32, IC_SMBUS_INTR::Register>,
-    pub(crate) IC_SMBUS_INTR_MASK: ReadWrite<u32, IC_SMBUS_INTR::Register>,
-    pub(crate) IC_SMBUS_INTR_RAW_STATUS: ReadOnly<u32, IC_SMBUS_INTR::Register>,
-    pub(crate) IC_CLR_SMBUS_INTR: WriteOnly<u32, IC_SMBUS_INTR::Register>,
-    pub(crate) IC_OPTIONAL_SAR: ReadWrite<u32, IC_OPTION_SAR::Register>,
-    pub(crate) IC_SMBUS_UDID_LSB: ReadWrite<u32, IC_SMBUS_UDID_LSB::Register>,
-
-    _reserved: [u32; 5], // e0-f0
-    pub(crate) IC_COMP_PARAM_1: ReadOnly<u32, IC_COMP_PARAM_1::Register>,
-    pub(crate) IC_COMP_VERSION: ReadOnly<u32, IC_COMP_VERSION::Register>,
-    pub(crate) IC_COMP_TYPE: ReadOnly<u32, IC_COMP_TYPE::Register>,
+macro_rules! dw_apb_i2c_registers {
+    ($($offset:literal => $name:ident: $mode:ident<$reg:ty>,)*) => {
+        #[allow(non_snake_case)]
+        #[allow(dead_code)]
+        impl<A: I2cDwRegAccess> DwApbI2cRegistersRef<A> {
+            $(
+                #[inline]
+                pub(crate) fn $name(&self) -> DwReg<'_, $reg, $mode, A> {
+                    DwReg {
+                        regs: self,
+                        offset: $offset,
+                        _reg: PhantomData,
+                    }
+                }
+            )*
+        }
+    };
+}
+
+dw_apb_i2c_registers! {
+    0x00 => IC_CON: Rw<IC_CON::Register>,
+    0x04 => IC_TAR: Rw<IC_TAR::Register>,
+    0x08 => IC_SAR: Rw<IC_SAR::Register>,
+    0x0c => IC_HS_MADDR: Rw<IC_HS_MADDR::Register>,
+    0x10 => IC_DATA_CMD: Rw<IC_DATA_CMD::Register>,
+    0x14 => IC_SS_OR_UFM_SCL_HCNT: Rw<IC_GENERAL_CNT::Register>,
+    0x18 => IC_SS_OR_UFM_SCL_LCNT: Rw<IC_GENERAL_CNT::Register>,
+    0x1c => IC_FS_SCL_HCNT_OR_UFM_TBUF_CNT: Rw<IC_GENERAL_CNT::Register>,
+    0x20 => IC_FS_SCL_LCNT: Rw<IC_GENERAL_CNT::Register>,
+    0x24 => IC_HS_SCL_HCNT: Rw<IC_GENERAL_CNT::Register>,
+    0x28 => IC_HS_SCL_LCNT: Rw<IC_GENERAL_CNT::Register>,
+    0x2c => IC_INTR_STAT: Ro<IC_INTR::Register>,
+    0x30 => IC_INTR_MASK: Rw<IC_INTR::Register>,
+    0x34 => IC_RAW_INTR_STAT: Ro<IC_INTR::Register>,
+    0x38 => IC_RX_TL: Rw<IC_RX_TL::Register>,
+    0x3c => IC_TX_TL: Rw<IC_TX_TL::Register>,
+    0x40 => IC_CLR_INTR: Ro<IC_GENERAL_CLR::Register>,
+    0x44 => IC_CLR_RX_UNDER: Ro<IC_GENERAL_CLR::Register>,
+    0x48 => IC_CLR_RX_OVER: Ro<IC_GENERAL_CLR::Register>,
+    0x4c => IC_CLR_TX_OVER: Ro<IC_GENERAL_CLR::Register>,
+    0x50 => IC_CLR_RD_REQ: Ro<IC_GENERAL_CLR::Register>,
+    0x54 => IC_CLR_TX_ABRT: Ro<IC_GENERAL_CLR::Register>,
+    0x58 => IC_CLR_RX_DONE: Ro<IC_GENERAL_CLR::Register>,
+    0x5c => IC_CLR_ACTIVITY: Ro<IC_GENERAL_CLR::Register>,
+    0x60 => IC_CLR_STOP_DET: Ro<IC_GENERAL_CLR::Register>,
+    0x64 => IC_CLR_START_DET: Ro<IC_GENERAL_CLR::Register>,
+    0x68 => IC_CLR_GEN_CALL: Ro<IC_GENERAL_CLR::Register>,
+    0x6c => IC_ENABLE: Rw<IC_ENABLE::Register>,
+    0x70 => IC_STATUS: Ro<IC_STATUS::Register>,
+    0x74 => IC_TXFLR: Ro<IC_GENERAL_FLR::Register>,
+    0x78 => IC_RXFLR: Ro<IC_GENERAL_FLR::Register>,
+    0x7c => IC_SDA_HOLD: Rw<IC_SDA_HOLD::Register>,
+    0x80 => IC_TX_ABRT_SOURCE: Ro<IC_TX_ABRT_SOURCE::Register>,
+    0x84 => IC_SLV_DATA_NACK_ONLY: Rw<IC_SLV_DATA_NACK_ONLY::Register>,
+    0x88 => IC_DMA_CR: Rw<IC_DMA_CR::Register>,
+    0x8c => IC_DMA_TDLR: Rw<IC_GENERAL_FLR::Register>,
+    0x90 => IC_DMA_RDLR: Rw<IC_GENERAL_FLR::Register>,
+    0x94 => IC_SDA_SETUP: Rw<IC_SDA_SETUP::Register>,
+    0x98 => IC_ACK_GENERAL_CALL: Rw<IC_ACK_GENERAL_CALL::Register>,
+    0x9c => IC_ENABLE_STATUS: Ro<IC_ENABLE_STATUS::Register>,
+
+    0xa0 => IC_FS_OR_UFM_SPKLEN: Rw<IC_GENERAL_SPKLEN::Register>,
+    0xa4 => IC_HS_SPKLEN: Rw<IC_GENERAL_SPKLEN::Register>,
+    0xa8 => IC_CLR_RESTART_DET: Ro<IC_GENERAL_CLR::Register>,
+
+    0xac => IC_SCL_STUCK_AT_LOW_TIMEOUT: Rw<IC_GENERAL_TIMEOUT::Register>,
+    0xb0 => IC_SDA_STUCK_AT_LOW_TIMEOUT: Rw<IC_GENERAL_TIMEOUT::Register>,
+    0xb4 => IC_CLR_SCL_STUCK_DET: Ro<IC_GENERAL_CLR::Register>,
+    0xb8 => IC_DEVICE_ID: Ro<IC_DEVICE_ID::Register>,
+
+    0xbc => IC_SMBUS_CLOCK_LOW_SEXT: Rw<IC_GENERAL_TIMEOUT::Register>,
+    0xc0 => IC_SMBUS_CLOCK_LOW_MEXT: Rw<IC_GENERAL_TIMEOUT::Register>,
+    0xc4 => IC_SMBUS_THIGH_MAX_IDLE_COUNT: Rw<IC_GENERAL_CNT::Register>,
+    0xc8 => IC_SMBUS_INTR_STAT: Ro<IC_SMBUS_INTR::Register>,
+    0xcc => IC_SMBUS_INTR_MASK: Rw<IC_SMBUS_INTR::Register>,
+    0xd0 => IC_SMBUS_INTR_RAW_STATUS: Ro<IC_SMBUS_INTR::Register>,
+    0xd4 => IC_CLR_SMBUS_INTR: Wo<IC_SMBUS_INTR::Register>,
+    0xd8 => IC_OPTIONAL_SAR: Rw<IC_OPTION_SAR::Register>,
+    0xdc => IC_SMBUS_UDID_LSB: Rw<IC_SMBUS_UDID_LSB::Register>,
+
+    0xf4 => IC_COMP_PARAM_1: Ro<IC_COMP_PARAM_1::Register>,
+    0xf8 => IC_COMP_VERSION: Ro<IC_COMP_VERSION::Register>,
+    0xfc => IC_COMP_TYPE: Ro<IC_COMP_TYPE::Register>,
 }
 
 register_bitfields![u32,
@@ -311,7 +498,7 @@ register_bitfields![u32,
         APR_RST_CMD_DET OFFSET(5) NUMBITS(1) [],
         ARP_PREPARE_CMD_DET OFFSET(4) NUMBITS(1) [],
         HOST_NOTIFY_MST_DET OFFSET(3) NUMBITS(1) [],
-        QUICK_CMD_DET OFFSET(1) NUMBITS(1) [],
+        QUICK_CMD_DET OFFSET(2) NUMBITS(1) [],
         MST_CLOCK_EXTND_TIMEOUT OFFSET(1) NUMBITS(1) [],
         SLV_CLOCK_EXTND_TIMEOUT OFFSET(0) NUMBITS(1) [],
      ],
@@ -0,0 +1,18 @@
+use osl::error::Result;
+
+/// Default time to wait for the bus semaphore, as Linux waits for the
+/// Baytrail PUNIT semaphore
+pub(crate) const DW_I2C_SEMAPHORE_TIMEOUT_US: u64 = 500_000;
+
+/// Hardware semaphore of a controller shared with firmware, like the
+/// Baytrail PUNIT or the AMD PSP semaphore
+///
+/// Modeled after Linux `acquire_lock`/`release_lock` of i2c-designware,
+/// see `I2cDwMasterDriver::set_bus_semaphore`.
+pub trait I2cDwBusSemaphore: Sync {
+    /// Take the controller from the firmware, giving up after
+    /// `timeout_us`
+    fn acquire(&self, timeout_us: u64) -> Result<()>;
+    /// Give the controller back to the firmware
+    fn release(&self);
+}
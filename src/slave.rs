@@ -0,0 +1,446 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use osl::{
+    error::{to_error, Errno, Result},
+    sync::{SpinLock, new_spinlock},
+    driver::irq,
+    driver::irq::{to_irq_return, ReturnEnum},
+};
+
+#[cfg(feature = "linux")]
+use osl::sync::Arc;
+
+#[allow(unused_imports)]
+use tock_registers::LocalRegisterCopy;
+
+use crate::{
+    common::DwI2cStatus,
+    master::I2cDeviceId,
+    registers::*,
+    DwI2cBusTiming, DwI2cHwParams, I2cDwCoreDriver, I2cDwDriverConfig, I2cDwMmio, I2cDwRegAccess,
+};
+
+/// Target mode events, modeled after Linux `i2c_slave_event`
+#[derive(Debug)]
+pub enum I2cDwSlaveEvent<'a> {
+    /// A master started a write to us
+    WriteRequested,
+    /// A master wrote a byte to us
+    WriteReceived(u8),
+    /// A master started a read from us, fill in the first byte
+    ReadRequested(&'a mut u8),
+    /// The previous byte was read by the master, fill in the next one
+    ReadProcessed(&'a mut u8),
+    /// A master started a General Call write
+    GeneralCallRequested,
+    /// A byte of a General Call write
+    GeneralCallReceived(u8),
+    /// A STOP ended the transfer addressed to us
+    Stop,
+    /// SMBSUS# was asserted (`true`) or deasserted (`false`) on the bus
+    SmbusSuspend(bool),
+}
+
+/// Target mode event handler, called from the irq handler
+pub type I2cDwSlaveHandler = fn(event: I2cDwSlaveEvent<'_>);
+
+/// Platform logic telling which target address a master addressed, for
+/// a block answering at both IC_SAR and IC_OPTIONAL_SAR
+///
+/// The DW core does not report which of the two matched, so a second
+/// address is only accepted with this, see
+/// `I2cDwSlaveDriver::register_optional_slave`.
+pub trait I2cDwTargetAddrMatch: Sync {
+    /// Address of the transfer starting now, None if unknown
+    fn matched_addr(&self) -> Option<u16>;
+}
+
+/// The second target at IC_OPTIONAL_SAR
+struct OptionalTarget {
+    addr: u16,
+    handler: I2cDwSlaveHandler,
+    addr_match: &'static dyn I2cDwTargetAddrMatch,
+}
+
+/// The I2cDesignware target mode Driver
+#[allow(dead_code)]
+pub struct I2cDwSlaveDriver<A: I2cDwRegAccess = I2cDwMmio> {
+    /// I2c Config  register set value
+    cfg: LocalRegisterCopy<u32, IC_CON::Register>,
+    /// core Driver
+    driver: I2cDwCoreDriver<A>,
+    /// Bus timing computed by `setup`
+    bus: DwI2cBusTiming,
+    /// Target address
+    addr: u16,
+    /// Event handler, set while a target is registered
+    handler: Option<I2cDwSlaveHandler>,
+    /// Second 7-bit target, with its own handler
+    optional: Option<OptionalTarget>,
+    /// The transfer in progress is addressed to `optional`, only used
+    /// by the irq handler
+    optional_hit: AtomicBool,
+    /// ACK General Calls
+    ack_general_call: bool,
+
+    /// Read/write progress of the current transfer, only used
+    /// by the irq handler
+    #[cfg(feature = "linux")]
+    status: Arc<SpinLock<DwI2cStatus>>,
+    #[cfg(feature = "arceos")]
+    status: SpinLock<DwI2cStatus>,
+}
+
+impl I2cDwSlaveDriver {
+    /// Create a new target mode I2cDesignwarDriver
+    ///
+    /// ## Safety
+    ///
+    /// - `base_addr` must be aligned, non-null, and dereferencable as the
+    ///   DW APB I2C register map.
+    /// - `*base_addr` must be valid for the program duration.
+    pub unsafe fn new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Self {
+        // SAFETY: as promised by the caller
+        Self::with_access(config, unsafe { I2cDwMmio::new(base_addr) })
+    }
+
+    /// Create a new target mode I2cDesignwarDriver, failing with `Errno::InvalidArgs`
+    /// if `base_addr` is null or not 32-bit aligned
+    ///
+    /// `setup` probes the block before writing to it.
+    ///
+    /// ## Safety
+    ///
+    /// - `base_addr`, if non-null and aligned, must be dereferencable as
+    ///   the DW APB I2C register map.
+    /// - `*base_addr` must be valid for the program duration.
+    pub unsafe fn try_new(config: I2cDwDriverConfig, base_addr: *mut u8) -> Result<Self> {
+        // SAFETY: as promised by the caller
+        Ok(Self::with_access(config, unsafe { I2cDwMmio::try_new(base_addr) }?))
+    }
+}
+
+impl<A: I2cDwRegAccess> I2cDwSlaveDriver<A> {
+    /// Create a new target mode I2cDesignwarDriver accessing its
+    /// registers through `access`
+    pub fn with_access(config: I2cDwDriverConfig, access: A) -> Self {
+        Self {
+            cfg: LocalRegisterCopy::new(0),
+            bus: DwI2cBusTiming::new(&config.timing, config.clk_rate_khz),
+            driver: I2cDwCoreDriver::new(config, access),
+            addr: 0,
+            handler: None,
+            optional: None,
+            optional_hit: AtomicBool::new(false),
+            ack_general_call: false,
+            #[cfg(feature = "linux")]
+            status: Arc::pin_init(new_spinlock!(DwI2cStatus::empty())).unwrap(),
+            #[cfg(feature = "arceos")]
+            status: new_spinlock!(DwI2cStatus::empty()),
+        }
+    }
+
+    /// Check the registers are a DW APB I2C block, reading IC_COMP_TYPE
+    /// and IC_COMP_PARAM_1 only, and pick the register access mode
+    ///
+    /// Fails with `Errno::NoSuchDevice` otherwise. The block must be
+    /// clocked and out of reset; `setup` probes by itself.
+    pub fn probe(&mut self) -> Result<()> {
+        self.driver.probe()
+    }
+
+    /// What the block was synthesized with, None until probed
+    pub fn hw_params(&self) -> Option<DwI2cHwParams> {
+        self.driver.hw_params
+    }
+
+    /// Initialize the designware I2C target mode driver config
+    pub fn setup(&mut self) -> Result<()> {
+        // probe and speed check must be the first step
+        self.probe()?;
+        self.driver.speed_check(&mut self.bus)?;
+        // init config
+        self.config_init();
+        self.driver.sda_hold_time_init(&mut self.bus)?;
+
+        // Initialize the designware I2C target hardware, the adapter
+        // stays disabled until a target is registered
+        self.slave_setup();
+        Ok(())
+    }
+
+    /// cfg init
+    fn config_init(&mut self) {
+        self.cfg.modify(IC_CON::MASTER_MODE.val(0));
+        self.cfg.modify(IC_CON::IC_SLAVE_DISABLE.val(0));
+        self.cfg.modify(IC_CON::IC_RESTART_EN.val(1));
+        self.cfg.modify(IC_CON::RX_FIFO_FULL_HLD_CTRL.val(1));
+        self.cfg.modify(IC_CON::STOP_DET_IFADDRESSED.val(1));
+        self.driver.cfg_init_speed(&self.bus, &mut self.cfg);
+    }
+
+    /// Start answering as target at `addr`, a 10-bit address above 0x7f
+    pub fn register_slave(&mut self, addr: u16, handler: I2cDwSlaveHandler) -> Result<()> {
+        if self.handler.is_some() {
+            log_err!("target 0x{:x} already registered", self.addr);
+            return to_error(Errno::Busy);
+        }
+
+        if addr > 0x3ff {
+            log_err!("invalid target address 0x{:x}", addr);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        self.cfg.modify(IC_CON::IC_10BITADDR_SLAVE.val((addr > 0x7f).into()));
+        self.cfg.modify(IC_CON::OPTIONAL_SAR_CTRL.val(0));
+        self.addr = addr;
+        self.handler = Some(handler);
+        self.optional = None;
+        self.start_target();
+        log_info!("target registered at 0x{:x}", addr);
+        Ok(())
+    }
+
+    /// Also answer at the 7-bit `addr` through IC_OPTIONAL_SAR, with the
+    /// events of transfers to it going to `handler`
+    ///
+    /// The DW core does not tell which address matched, `addr_match` of
+    /// the platform does. Transfers it can not tell apart go to the
+    /// target of `register_slave`, as do General Calls. SMBSUS# events go
+    /// to both. Fails with `Errno::InvalidArgs` without a target
+    /// registered, `Errno::Busy` if a second one is registered already.
+    pub fn register_optional_slave(
+        &mut self,
+        addr: u16,
+        handler: I2cDwSlaveHandler,
+        addr_match: &'static dyn I2cDwTargetAddrMatch,
+    ) -> Result<()> {
+        if self.handler.is_none() {
+            log_err!("no target registered for optional address 0x{:x}", addr);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        if let Some(optional) = &self.optional {
+            log_err!("optional target 0x{:x} already registered", optional.addr);
+            return to_error(Errno::Busy);
+        }
+
+        if addr > 0x7f || addr == self.addr {
+            log_err!("invalid optional target address 0x{:x}", addr);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        self.cfg.modify(IC_CON::OPTIONAL_SAR_CTRL.val(1));
+        self.optional = Some(OptionalTarget { addr, handler, addr_match });
+        self.start_target();
+        log_info!("optional target registered at 0x{:x}", addr);
+        Ok(())
+    }
+
+    /// Program the target addresses and enable the adapter
+    fn start_target(&self) {
+        *self.status.lock() = DwI2cStatus::empty();
+        self.optional_hit.store(false, Ordering::Relaxed);
+
+        self.slave_setup();
+        self.driver.write_ic_sar(self.addr);
+        if let Some(optional) = &self.optional {
+            self.driver.write_optional_sar(optional.addr);
+        }
+        self.driver.enable_controler();
+    }
+
+    /// ACK (`true`) or NACK (`false`) General Calls, disabled by default
+    ///
+    /// The General Call payload is reported as `GeneralCallRequested` and
+    /// `GeneralCallReceived` events, apart from writes addressed to us.
+    pub fn enable_general_call(&mut self, enable: bool) {
+        self.ack_general_call = enable;
+        self.driver.enable_ack_general_call(enable);
+        self.driver.write_interrupt_mask(&self.slave_intr_mask());
+    }
+
+    /// Return the Device ID we answer Device ID reads with
+    ///
+    /// IC_DEVICE_ID is fixed when the IP is synthesized
+    /// (IC_DEVICE_ID_VALUE) and can not be changed by software.
+    pub fn device_id(&self) -> I2cDeviceId {
+        I2cDeviceId::from_raw(self.driver.ic_device_id())
+    }
+
+    /// Stop answering as target, at both addresses
+    pub fn unregister_slave(&mut self) -> Result<()> {
+        if self.handler.is_none() {
+            return to_error(Errno::InvalidArgs);
+        }
+
+        self.driver.disable();
+        self.handler = None;
+        self.optional = None;
+        Ok(())
+    }
+
+    /// Ask the platform which target the transfer starting now is
+    /// addressed to, and return its handler
+    fn addressed_handler(&self, handler: I2cDwSlaveHandler) -> I2cDwSlaveHandler {
+        let optional = match &self.optional {
+            Some(optional) => optional,
+            None => return handler,
+        };
+
+        let hit = match optional.addr_match.matched_addr() {
+            Some(addr) if addr == optional.addr => true,
+            Some(addr) if addr == self.addr => false,
+            matched => {
+                log_warn!("unknown matched target address {:x?}", matched);
+                false
+            }
+        };
+        self.optional_hit.store(hit, Ordering::Relaxed);
+        self.current_handler(handler)
+    }
+
+    /// Handler of the target the transfer in progress is addressed to
+    fn current_handler(&self, handler: I2cDwSlaveHandler) -> I2cDwSlaveHandler {
+        match &self.optional {
+            Some(optional) if self.optional_hit.load(Ordering::Relaxed) => optional.handler,
+            _ => handler,
+        }
+    }
+
+    /// Interrupt service routine. This gets called whenever an I2C target
+    /// interrupt occurs
+    pub fn irq_handler(&self) -> irq::Return {
+        let handler = match self.handler {
+            Some(handler) => handler,
+            None => return to_irq_return(ReturnEnum::None),
+        };
+
+        let smbus_stat = self.driver.read_and_clean_smbus_intrbits();
+        if smbus_stat.is_set(IC_SMBUS_INTR::SMBUS_SUSPEND_DET) {
+            let asserted = self.driver.ic_status().is_set(IC_STATUS::SMBUS_SUSPEND_STATUS);
+            handler(I2cDwSlaveEvent::SmbusSuspend(asserted));
+            if let Some(optional) = &self.optional {
+                (optional.handler)(I2cDwSlaveEvent::SmbusSuspend(asserted));
+            }
+        }
+
+        let enable = self.driver.ic_enable();
+        let stat = self.driver.ic_raw_intr_stat().get();
+        // check raw intr stat
+        if !enable.is_set(IC_ENABLE::ENABLE) || (stat & !0b100000000) == 0 {
+            if smbus_stat.get() != 0 {
+                return to_irq_return(ReturnEnum::Handled);
+            }
+            return to_irq_return(ReturnEnum::None);
+        }
+
+        let ic_status = self.driver.ic_status();
+        let (stat, _) = self.driver.read_and_clean_intrbits(0);
+        log_debug!("target irq stat: {:x}, status: {:x}", stat.get(), ic_status.get());
+
+        let mut status = self.status.lock();
+        let mut target = self.current_handler(handler);
+        // GEN_CALL is raised once the General Call address is ACKed,
+        // the payload follows through the RX FIFO
+        if stat.is_set(IC_INTR::GEN_CALL) {
+            *status = DwI2cStatus::GeneralCallInProgress;
+            handler(I2cDwSlaveEvent::GeneralCallRequested);
+        }
+
+        if stat.is_set(IC_INTR::RX_FULL) && status.contains(DwI2cStatus::GeneralCallInProgress) {
+            loop {
+                let data = self.driver.ic_data_cmd().read(IC_DATA_CMD::DAT) as u8;
+                handler(I2cDwSlaveEvent::GeneralCallReceived(data));
+                if !self.driver.ic_status().is_set(IC_STATUS::RFNE) {
+                    break;
+                }
+            }
+        } else if stat.is_set(IC_INTR::RX_FULL) {
+            let mut requested = false;
+            if !status.contains(DwI2cStatus::WriteInProgress) {
+                status.insert(DwI2cStatus::WriteInProgress);
+                status.remove(DwI2cStatus::ReadInProgress);
+                target = self.addressed_handler(handler);
+                target(I2cDwSlaveEvent::WriteRequested);
+                requested = true;
+            }
+
+            loop {
+                let data = self.driver.ic_data_cmd();
+                // A repeated START addressed to us begins a new write
+                if data.is_set(IC_DATA_CMD::FIRST_DATA_BYTE) && !requested {
+                    target = self.addressed_handler(handler);
+                    target(I2cDwSlaveEvent::WriteRequested);
+                }
+                requested = false;
+                target(I2cDwSlaveEvent::WriteReceived(data.read(IC_DATA_CMD::DAT) as u8));
+                if !self.driver.ic_status().is_set(IC_STATUS::RFNE) {
+                    break;
+                }
+            }
+        }
+
+        if stat.is_set(IC_INTR::RD_REQ) && ic_status.is_set(IC_STATUS::SLV_ACTIVITY) {
+            let mut val = 0u8;
+            if !status.contains(DwI2cStatus::ReadInProgress) {
+                status.insert(DwI2cStatus::ReadInProgress);
+                status.remove(DwI2cStatus::WriteInProgress);
+                target = self.addressed_handler(handler);
+                target(I2cDwSlaveEvent::ReadRequested(&mut val));
+            } else {
+                target(I2cDwSlaveEvent::ReadProcessed(&mut val));
+            }
+            let mut cmd: LocalRegisterCopy<u32, IC_DATA_CMD::Register> = LocalRegisterCopy::new(0);
+            cmd.modify(IC_DATA_CMD::DAT.val(val.into()));
+            self.driver.write_ic_data_cmd(&cmd);
+        }
+
+        if stat.is_set(IC_INTR::STOP_DET) {
+            *status = DwI2cStatus::empty();
+            target(I2cDwSlaveEvent::Stop);
+            self.optional_hit.store(false, Ordering::Relaxed);
+        }
+
+        to_irq_return(ReturnEnum::Handled)
+    }
+
+    fn slave_setup(&self) {
+        // Disable the adapter
+        self.driver.disable_controler();
+        // Write SDA hold time if supported
+        self.driver.write_sda_hold_time(&self.bus);
+        // Write fifo, interrupt on every received byte
+        self.driver.write_fifo(0, 0);
+        // set IC_CON
+        self.driver.write_ic_con(&self.cfg);
+        // Write General Call ACK and interrupt mask
+        self.driver.enable_ack_general_call(self.ack_general_call);
+        self.driver.write_interrupt_mask(&self.slave_intr_mask());
+        self.driver.write_smbus_interrupt_mask(&Self::slave_default_smbus_intr_mask());
+    }
+
+    fn slave_default_intr_mask() -> LocalRegisterCopy<u32, IC_INTR::Register> {
+        let mut mask = LocalRegisterCopy::new(0);
+        mask.modify(IC_INTR::RX_FULL.val(0b1));
+        mask.modify(IC_INTR::RX_UNDER.val(0b1));
+        mask.modify(IC_INTR::RD_REQ.val(0b1));
+        mask.modify(IC_INTR::RX_DONE.val(0b1));
+        mask.modify(IC_INTR::STOP_DET.val(0b1));
+        mask
+    }
+
+    fn slave_intr_mask(&self) -> LocalRegisterCopy<u32, IC_INTR::Register> {
+        let mut mask = Self::slave_default_intr_mask();
+        if self.ack_general_call {
+            mask.modify(IC_INTR::GEN_CALL.val(0b1));
+        }
+        mask
+    }
+
+    fn slave_default_smbus_intr_mask() -> LocalRegisterCopy<u32, IC_SMBUS_INTR::Register> {
+        let mut mask = LocalRegisterCopy::new(0);
+        mask.modify(IC_SMBUS_INTR::SMBUS_SUSPEND_DET.val(0b1));
+        mask
+    }
+}
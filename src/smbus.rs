@@ -0,0 +1,94 @@
+use osl::error::{to_error, Errno, Result};
+
+/// SMBus Alert Response Address
+pub(crate) const SMBUS_ARA_ADDR: u16 = 0x0c;
+
+/// Max number of devices answering the ARA handled per alert, guards
+/// against a device that keeps SMBALERT# asserted
+pub(crate) const SMBUS_ALERT_MAX_LOOPS: usize = 8;
+
+/// Max number of registered SMBus alert handlers
+pub const SMBUS_ALERT_MAX_HANDLERS: usize = 8;
+
+/// SMBus alert handler
+///
+/// Called with the 7-bit address of the device which answered the
+/// Alert Response Address, and the flag (bit 0) of its answer. It runs
+/// from `smbus_alert_process` with the bus released, so it can read the
+/// status of the device.
+pub type SmbusAlertHandler = fn(addr: u16, flag: u8);
+
+/// SMBus Host Notify handler
+///
+/// Called from the irq handler with the 7-bit address of the notifying
+/// device and the 16-bit data word it sent.
+pub type SmbusHostNotifyHandler = fn(addr: u16, data: u16);
+
+/// Length of a Host Notify message in the RX FIFO: device address,
+/// data low byte and data high byte
+pub(crate) const SMBUS_HOST_NOTIFY_LEN: u32 = 3;
+
+/// Per-address SMBus alert handlers registered by client drivers
+pub(crate) struct SmbusAlertHandlers {
+    handlers: [Option<(u16, SmbusAlertHandler)>; SMBUS_ALERT_MAX_HANDLERS],
+}
+
+impl Default for SmbusAlertHandlers {
+    fn default() -> Self {
+        Self {
+            handlers: [None; SMBUS_ALERT_MAX_HANDLERS],
+        }
+    }
+}
+
+impl SmbusAlertHandlers {
+    pub(crate) fn register(&mut self, addr: u16, handler: SmbusAlertHandler) -> Result<()> {
+        if addr > 0x7f || addr == SMBUS_ARA_ADDR {
+            log_err!("invalid SMBus alert address 0x{:x}", addr);
+            return to_error(Errno::InvalidArgs);
+        }
+
+        if self.find(addr).is_some() {
+            log_err!("SMBus alert handler for 0x{:x} already registered", addr);
+            return to_error(Errno::Busy);
+        }
+
+        match self.handlers.iter_mut().find(|h| h.is_none()) {
+            Some(slot) => {
+                *slot = Some((addr, handler));
+                Ok(())
+            }
+            None => {
+                log_err!("no free SMBus alert handler slot for 0x{:x}", addr);
+                to_error(Errno::Busy)
+            }
+        }
+    }
+
+    pub(crate) fn unregister(&mut self, addr: u16) -> Result<()> {
+        match self
+            .handlers
+            .iter_mut()
+            .find(|h| matches!(h, Some((a, _)) if *a == addr))
+        {
+            Some(slot) => {
+                *slot = None;
+                Ok(())
+            }
+            None => to_error(Errno::InvalidArgs),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.handlers.iter().all(|h| h.is_none())
+    }
+
+    pub(crate) fn find(&self, addr: u16) -> Option<SmbusAlertHandler> {
+        self.handlers
+            .iter()
+            .flatten()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, handler)| *handler)
+    }
+}